@@ -3,10 +3,17 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 
-use lrad::{error::Result, LradCli};
+use lrad::{
+    config::DAEMON_CONFIG_PATH, error::Result, InitOptions, LradCli, LradDaemon, PushOptions,
+    Verification,
+};
 
 use futures::prelude::*;
 use std::env;
+use std::path::Path;
+
+const SYSTEMD_UNIT_PATH: &str = "/etc/systemd/system/lrad-daemon.service";
+const DAEMON_BINARY_PATH: &str = "/usr/bin/lrad-daemon";
 
 fn main() -> Result<()> {
     let dotenv_res = dotenv::dotenv();
@@ -22,31 +29,143 @@ fn main() -> Result<()> {
         (version: crate_version!())
         (author: crate_authors!())
         (about: "An update framework for hobbyist SBCs")
-        (@arg CONFIG: -c --config +takes_value "Sets a custom config file")
+        (@arg CONFIG: -c --config +takes_value "Sets a custom config file, taken from the root of the repo if relative and not a daemon config")
         (@subcommand init =>
             (about: "Initializes the current git repo with a .lrad.toml configuration file.")
+            (@arg DAEMON: --daemon "Instead scaffolds a daemon config and a systemd unit on this device")
+            (@arg SCAFFOLD: --scaffold "Also writes a starter Dockerfile and adds .env to .gitignore, keeping existing ones")
         )
         (@subcommand push =>
             (about: "Pushes this git repo to IPFS and updates the DNS link record in Cloudflare.")
+            (@arg INCLUDE_IGNORED: --("include-ignored") "Pushes even if HEAD has files matched by .gitignore, e.g. a force-added .env")
+            (@arg PIN_REMOTE: --("pin-remote") "Also pins the pushed content on the configured remote pinning service")
+            (@arg INCREMENTAL: --incremental "Only adds the files that changed since the last push from this repo")
+            (@arg IPNS_KEY: --ipns +takes_value "Publishes to the IPNS name of this IPFS node key instead of updating the DNS link record")
+        )
+        (@subcommand verify =>
+            (about: "Checks that what the DNS link record points to on IPFS has the same tree as the local HEAD.")
+        )
+        (@subcommand config =>
+            (about: "Inspects the configuration.")
+            (@subcommand show =>
+                (about: "Prints the configuration in effect, with credentials redacted.")
+                (@arg DAEMON: --daemon "Shows the daemon config on this device instead")
+            )
+        )
+        (@subcommand status =>
+            (about: "Reports whether what is deployed on this device is what the DNS link record points to.")
+            (@arg JSON: --json "Prints the status as JSON instead")
+            (@arg TARGET: --target +takes_value "Only reports the target with this dns_record_name")
+        )
+        (@subcommand rollback =>
+            (about: "Runs an earlier deployed image again on this device, by default the one before the current one.")
+            (@arg TO: --to +takes_value "The CID to roll back to instead")
+            (@arg LIST: --list "Lists the CIDs there are images to roll back to, most recent first")
+            (@arg TARGET: --target +takes_value "The dns_record_name of the target to roll back, if the daemon has several")
         )
         (@subcommand daemon =>
-            (about: "Starts daemon to deploy packages with")
+            (about: "Runs the deploy daemon in the foreground, like lrad-daemon. Reads the config given with -c, or /etc/lrad/lrad-daemon.toml.")
+            (@arg ONCE: --once "Looks up and deploys each target once, then exits, e.g. when run from cron")
         )
     )
     .get_matches();
 
-    if let Some(_matches) = matches.subcommand_matches("init") {
+    let config_path = matches.value_of("CONFIG").map(Path::new);
+    if let Some(init_matches) = matches.subcommand_matches("init") {
+        if init_matches.is_present("DAEMON") {
+            let config_path = config_path.unwrap_or_else(|| Path::new(DAEMON_CONFIG_PATH));
+            LradDaemon::try_init(
+                config_path,
+                Path::new(SYSTEMD_UNIT_PATH),
+                Path::new(DAEMON_BINARY_PATH),
+            )?;
+            info!(
+                "Successfully initialized! Edit {} and then run `systemctl enable --now lrad-daemon`.",
+                config_path.display()
+            );
+            return Ok(());
+        }
         let current_dir = env::current_dir()?;
-        LradCli::try_init(&current_dir)?;
+        let options = InitOptions {
+            scaffold: init_matches.is_present("SCAFFOLD"),
+        };
+        LradCli::try_init(&current_dir, config_path, options)?;
         info!("Successfully initialized! Please make sure to store any secrets securely.");
         Ok(())
-    } else if let Some(_matches) = matches.subcommand_matches("push") {
+    } else if let Some(matches) = matches.subcommand_matches("push") {
         let current_dir = env::current_dir()?;
-        let lrad = LradCli::try_load(&current_dir)?;
-        lrad.try_push().and_then(|hash| {
+        let lrad = LradCli::try_load(&current_dir, config_path)?;
+        let options = PushOptions {
+            include_ignored: matches.is_present("INCLUDE_IGNORED"),
+            pin_remote: matches.is_present("PIN_REMOTE"),
+            ipns_key: matches.value_of("IPNS_KEY").map(String::from),
+            incremental: matches.is_present("INCREMENTAL"),
+        };
+        lrad.try_push(options).and_then(|hash| {
             info!("Successfully pushed to IPFS! You can try cloning it from your local IPFS gateway: http://localhost:8080/ipfs/{}", hash);
             Ok(())
         }).wait()
+    } else if matches.subcommand_matches("verify").is_some() {
+        let lrad = LradCli::try_load(&env::current_dir()?, config_path)?;
+        let verification = actix::System::new("lrad").block_on(lrad.try_verify())?;
+        println!("{}", verification);
+        match verification {
+            Verification::Match { .. } => Ok(()),
+            _ => std::process::exit(1),
+        }
+    } else if let Some(show_matches) = matches
+        .subcommand_matches("config")
+        .and_then(|config_matches| config_matches.subcommand_matches("show"))
+    {
+        let rendered = if show_matches.is_present("DAEMON") {
+            LradDaemon::try_load(config_path)?.show_config()?
+        } else {
+            LradCli::try_load(&env::current_dir()?, config_path)?.show_config()?
+        };
+        print!("{}", rendered);
+        Ok(())
+    } else if let Some(status_matches) = matches.subcommand_matches("status") {
+        let daemon = LradDaemon::try_load(config_path)?;
+        let targets = match status_matches.value_of("TARGET") {
+            Some(dns_record_name) => vec![daemon.target(Some(dns_record_name))?],
+            None => daemon.targets().iter().collect(),
+        };
+        let mut sys = actix::System::new("lrad");
+        let statuses = targets
+            .into_iter()
+            .map(|target| sys.block_on(daemon.try_status(target)))
+            .collect::<Result<Vec<_>>>()?;
+        if status_matches.is_present("JSON") {
+            println!("{}", serde_json::to_string_pretty(&statuses)?);
+        } else {
+            for status in statuses {
+                println!("{}", status);
+            }
+        }
+        Ok(())
+    } else if let Some(rollback_matches) = matches.subcommand_matches("rollback") {
+        let daemon = LradDaemon::try_load(config_path)?;
+        let target = daemon.target(rollback_matches.value_of("TARGET"))?;
+        let mut sys = actix::System::new("lrad");
+        if rollback_matches.is_present("LIST") {
+            for cid in sys.block_on(daemon.deployed_cids(target))? {
+                println!("{}", cid);
+            }
+            return Ok(());
+        }
+        let cid = sys.block_on(daemon.try_rollback(target, rollback_matches.value_of("TO")))?;
+        info!("Successfully rolled back to {}!", cid);
+        Ok(())
+    } else if let Some(daemon_matches) = matches.subcommand_matches("daemon") {
+        let daemon = LradDaemon::try_load(config_path)?;
+        if daemon_matches.is_present("ONCE") {
+            if daemon.run_once()? {
+                info!("Successfully deployed!");
+            }
+            return Ok(());
+        }
+        info!("Daemon ready!");
+        lrad::actor::run(daemon)
     } else {
         Ok(())
     }
@@ -3,106 +3,64 @@ extern crate clap;
 #[macro_use]
 extern crate log;
 
-use ::actix::prelude::*;
 use ::actix::System;
 use futures::prelude::*;
-use lrad::{
-    dns::DnsTxtRecordResponse,
-    error::{Error, Result},
-    LradDaemon,
-};
+use lrad::{error::Result, LradDaemon};
 
 use std::env;
 use std::path::Path;
-use std::time::{Duration, Instant};
-
-const CONFIG_FILE_PATH: &'static str = "/etc/lrad/lrad-daemon.toml";
+use std::sync::mpsc;
 
 fn main() -> Result<()> {
     if env::var("RUST_LOG").is_err() {
         env::set_var("RUST_LOG", "lrad=info,lrad-daemon=info");
     }
     env_logger::init();
-    debug!("Loading configuration from {}", CONFIG_FILE_PATH);
-    let daemon = LradDaemon::try_load(Path::new(CONFIG_FILE_PATH))?;
-    info!("Daemon ready!");
-
-    let sys = System::new("lrad-daemon");
-    DaemonActor {
-        daemon,
-        record: None,
+    let matches = clap_app!(LRAD_DAEMON =>
+        (version: crate_version!())
+        (author: crate_authors!())
+        (about: "Deploys updates published with lrad")
+        (@arg CONFIG: -c --config +takes_value "Sets a custom config file instead of $LRAD_DAEMON_CONFIG or /etc/lrad/lrad-daemon.toml, - for stdin")
+        (@arg ONCE: --once "Looks up and deploys each target once, then exits, e.g. when run from cron")
+        (@arg DRY_RUN: --("dry-run") "Looks up and builds each target once, only logging the containers it would start and remove")
+        (@arg FROM_DIR: --("from-dir") +takes_value "Builds and runs once from a local directory, skipping DNS and IPFS")
+    )
+    .get_matches();
+    let daemon = LradDaemon::try_load(matches.value_of("CONFIG").map(Path::new))?;
+
+    if let Some(from_dir) = matches.value_of("FROM_DIR") {
+        info!("Deploying from local directory {}", from_dir);
+        let (tx, rx) = mpsc::channel();
+        actix::run(|| {
+            daemon
+                .try_deploy_from_dir(Path::new(from_dir))
+                .then(move |res| {
+                    System::current().stop();
+                    tx.send(res).unwrap();
+                    Ok(())
+                })
+        });
+        rx.recv().unwrap()?;
+        info!("Successfully deployed!");
+        return Ok(());
     }
-    .start();
-
-    sys.run();
-    Ok(())
-}
-
-struct DnsLookup;
-struct Deploy;
-
-impl Message for Deploy {
-    type Result = Result<()>;
-}
-
-impl Message for DnsLookup {
-    type Result = Result<()>;
-}
-
-struct DaemonActor {
-    daemon: LradDaemon,
-    record: Option<DnsTxtRecordResponse>,
-}
-
-impl Actor for DaemonActor {
-    type Context = Context<Self>;
-
-    fn started(&mut self, ctx: &mut Context<Self>) {
-        ctx.notify(DnsLookup {})
+    if matches.is_present("DRY_RUN") {
+        let mut sys = System::new("lrad-daemon");
+        for target in daemon.targets() {
+            info!("Dry run of {}", target.dns_record_name);
+            let record = sys.block_on(daemon.try_lookup_txt_record(target))?;
+            let cid = record.as_ref().and_then(|record| record.as_hash());
+            sys.block_on(daemon.try_deploy_dry_run(target, cid.as_ref().map(String::as_str)))?;
+        }
+        return Ok(());
     }
-}
-
-impl Handler<DnsLookup> for DaemonActor {
-    type Result = ResponseActFuture<Self, (), Error>;
-
-    fn handle(&mut self, msg: DnsLookup, ctx: &mut Context<Self>) -> Self::Result {
-        Box::new(
-            actix::fut::wrap_future::<_, Self>(self.daemon.try_lookup_txt_record()).map(
-                |new_record, actor, ctx| {
-                    info!("Received new DNS record, checking if a deployment is necessary.");
-                    if new_record != actor.record {
-                        actor.record = new_record;
-                        info!("Triggering deployment.");
-                        ctx.notify(Deploy {});
-                    } else {
-                        info!("No deployment necessary.");
-                    }
-                    info!("Sleeping for 300 secs.");
-                    ctx.notify_later(DnsLookup {}, Duration::from_secs(300));
-                },
-            ),
-        )
+    if matches.is_present("ONCE") {
+        if daemon.run_once()? {
+            info!("Successfully deployed!");
+        }
+        return Ok(());
     }
-}
-
-impl Handler<Deploy> for DaemonActor {
-    type Result = Result<()>;
+    info!("Daemon ready!");
 
-    fn handle(&mut self, msg: Deploy, ctx: &mut Context<Self>) -> Self::Result {
-        info!("Deploying updated code from IPFS.");
-        Arbiter::spawn(
-            self.daemon
-                .try_deploy()
-                .map(|res| {
-                    info!("Successfully deployed!");
-                    res
-                })
-                .map_err(|err| {
-                    error!("Error while deploying {:?}", err);
-                    err
-                })
-                .then(|x| Ok(())),
-        );
-        Ok(())
-    }
+    lrad::actor::run(daemon)
 }
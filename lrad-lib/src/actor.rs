@@ -0,0 +1,394 @@
+use ::actix::actors::signal;
+use ::actix::prelude::*;
+use ::actix::System;
+use futures::future;
+use futures::prelude::*;
+use std::time::Duration;
+
+use crate::dns::DnsTxtRecordResponse;
+use crate::error::{BoxFuture, Error, Result};
+use crate::health::{self, Health};
+use crate::{DeploySlot, LradDaemon};
+
+/// Follows every target of `daemon`, deploying whatever their records point to, until SIGTERM or
+/// SIGINT. Deploys in progress then get to finish first, unless the signal comes a second time.
+pub fn run(daemon: LradDaemon) -> Result<()> {
+    let sys = System::new("lrad-daemon");
+    let health = Health::new(daemon.targets());
+    if let Some(addr) = daemon.status_addr() {
+        health::serve(addr, health.clone())?;
+    }
+    DaemonActor::new(daemon, health).start();
+    sys.run();
+    Ok(())
+}
+
+/// Looks up the record of the target at this index in the daemon's targets
+struct DnsLookup {
+    target: usize,
+}
+/// Deploys the last seen record of the target at this index in the daemon's targets
+struct Deploy {
+    target: usize,
+}
+struct IntegrityCheck;
+
+/// Wait before retrying the first failed lookup of a target, doubled for each further failure
+const FAILED_LOOKUP_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Retries a target's lookups sooner than the next poll while they fail, e.g. right after boot
+/// when networking hasn't settled yet.
+#[derive(Default, Clone)]
+struct LookupBackoff {
+    failed_lookups: u32,
+}
+
+impl LookupBackoff {
+    /// How long to wait before the next lookup given how the last one went, never longer than
+    /// `poll_interval`.
+    fn next_delay(&mut self, succeeded: bool, poll_interval: Duration) -> Duration {
+        if succeeded {
+            self.failed_lookups = 0;
+            return poll_interval;
+        }
+        self.failed_lookups += 1;
+        FAILED_LOOKUP_RETRY_DELAY
+            .checked_mul(2u32.saturating_pow(self.failed_lookups - 1))
+            .map_or(poll_interval, |delay| delay.min(poll_interval))
+    }
+}
+
+impl Message for Deploy {
+    type Result = Result<()>;
+}
+
+impl Message for DnsLookup {
+    type Result = Result<()>;
+}
+
+impl Message for IntegrityCheck {
+    type Result = Result<()>;
+}
+
+/// Polls each target of the daemon and deploys whatever new record it finds.
+struct DaemonActor {
+    daemon: LradDaemon,
+    /// The last seen record of each target
+    records: Vec<Option<DnsTxtRecordResponse>>,
+    lookup_backoffs: Vec<LookupBackoff>,
+    /// A newer record abandons the deploy in progress for the same target
+    deploy_slots: Vec<DeploySlot>,
+    deploys_in_progress: usize,
+    health: Health,
+    /// Set once asked to stop, after which nothing new is looked up or deployed
+    stopping: bool,
+}
+
+impl DaemonActor {
+    fn new(daemon: LradDaemon, health: Health) -> Self {
+        let target_count = daemon.targets().len();
+        DaemonActor {
+            daemon,
+            records: vec![None; target_count],
+            lookup_backoffs: vec![LookupBackoff::default(); target_count],
+            deploy_slots: (0..target_count).map(|_| DeploySlot::default()).collect(),
+            deploys_in_progress: 0,
+            health,
+            stopping: false,
+        }
+    }
+
+    fn stop_once_idle(&self) {
+        if self.stopping && self.deploys_in_progress == 0 {
+            info!("Stopping.");
+            System::current().stop();
+        }
+    }
+}
+
+impl Actor for DaemonActor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        signal::ProcessSignals::from_registry()
+            .do_send(signal::Subscribe(ctx.address().recipient()));
+        for target in 0..self.records.len() {
+            ctx.notify(DnsLookup { target });
+        }
+        if let Some(interval) = self.daemon.integrity_check_interval() {
+            ctx.notify_later(IntegrityCheck {}, interval);
+        }
+    }
+}
+
+impl Handler<DnsLookup> for DaemonActor {
+    type Result = ResponseActFuture<Self, (), Error>;
+
+    fn handle(&mut self, msg: DnsLookup, ctx: &mut Context<Self>) -> Self::Result {
+        if self.stopping {
+            return Box::new(actix::fut::ok(()));
+        }
+        let target = msg.target;
+        let lookup = self
+            .daemon
+            .try_lookup_txt_record(&self.daemon.targets()[target]);
+        Box::new(
+            actix::fut::wrap_future::<_, Self>(lookup).then(move |res, actor, ctx| {
+                let dns_record_name = &actor.daemon.targets()[target].dns_record_name;
+                let succeeded = res.is_ok();
+                actor.health.lookup_finished(target, succeeded);
+                match res {
+                    Ok(new_record) => {
+                        info!(
+                            "Received new DNS record for {}, checking if a deployment is necessary.",
+                            dns_record_name
+                        );
+                        if actor.stopping {
+                            info!("Not deploying, as the daemon is stopping.");
+                        } else if DnsTxtRecordResponse::content_changed(
+                            &actor.records[target],
+                            &new_record,
+                        ) {
+                            actor.records[target] = new_record;
+                            info!("Triggering deployment.");
+                            ctx.notify(Deploy { target });
+                        } else {
+                            if new_record != actor.records[target] {
+                                debug!(
+                                    "TXT data changed to {:?} without pointing at other content.",
+                                    new_record.as_ref().map(|record| &record.txt_data)
+                                );
+                                actor.records[target] = new_record;
+                            }
+                            info!("No deployment necessary.");
+                        }
+                    }
+                    Err(err) => error!("DNS lookup for {} failed {:?}", dns_record_name, err),
+                }
+                if actor.stopping {
+                    return actix::fut::ok(());
+                }
+                let delay = actor.lookup_backoffs[target]
+                    .next_delay(succeeded, actor.daemon.next_poll_interval());
+                if succeeded {
+                    info!("Sleeping for {} secs.", delay.as_secs());
+                } else {
+                    warn!("Retrying DNS lookup in {} secs.", delay.as_secs());
+                }
+                ctx.notify_later(DnsLookup { target }, delay);
+                actix::fut::ok(())
+            }),
+        )
+    }
+}
+
+impl Handler<Deploy> for DaemonActor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: Deploy, ctx: &mut Context<Self>) -> Self::Result {
+        if self.stopping {
+            return Ok(());
+        }
+        let target = &self.daemon.targets()[msg.target];
+        info!("Deploying updated code for {} from IPFS.", target.dns_record_name);
+        let cid = self.records[msg.target]
+            .as_ref()
+            .and_then(DnsTxtRecordResponse::as_hash);
+        let notifier = self
+            .daemon
+            .deploy_notifier(target, cid.as_ref().map(String::as_str));
+        let health = self.health.clone();
+        let target_index = msg.target;
+        let deploy = self.deploy_slots[msg.target]
+            .start(self.daemon.try_deploy(target, cid.as_ref().map(String::as_str)));
+        self.deploys_in_progress += 1;
+        ctx.spawn(
+            deploy
+                .then(move |res| -> BoxFuture<()> {
+                    match res {
+                        Ok(Some(true)) => {
+                            info!("Successfully deployed!");
+                            health.deployed(target_index, cid);
+                            notifier.notify(&Ok(true))
+                        }
+                        Ok(Some(false)) => {
+                            warn!("Deploy finished without deploying anything.");
+                            notifier.notify(&Ok(false))
+                        }
+                        Ok(None) => {
+                            info!("Abandoned deploy in favor of a newer record.");
+                            Box::new(future::ok(()))
+                        }
+                        Err(err) => {
+                            error!("Error while deploying {:?}", err);
+                            notifier.notify(&Err(err))
+                        }
+                    }
+                })
+                .map_err(|_err| ())
+                .into_actor(self)
+                .then(|_res, actor, _ctx| {
+                    actor.deploys_in_progress -= 1;
+                    actor.stop_once_idle();
+                    actix::fut::ok(())
+                }),
+        );
+        Ok(())
+    }
+}
+
+impl Handler<IntegrityCheck> for DaemonActor {
+    type Result = Result<()>;
+
+    fn handle(&mut self, msg: IntegrityCheck, ctx: &mut Context<Self>) -> Self::Result {
+        if self.stopping {
+            return Ok(());
+        }
+        for record in self.records.iter().filter_map(Option::as_ref) {
+            info!("Checking integrity of deployed content.");
+            match self.daemon.try_check_integrity(record) {
+                Ok(true) => warn!("Deployed content was damaged and has been re-fetched."),
+                Ok(false) => info!("Deployed content is intact."),
+                Err(err) => error!("Error while checking integrity {:?}", err),
+            }
+        }
+        if let Some(interval) = self.daemon.integrity_check_interval() {
+            ctx.notify_later(IntegrityCheck {}, interval);
+        }
+        Ok(())
+    }
+}
+
+impl Handler<signal::Signal> for DaemonActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: signal::Signal, ctx: &mut Context<Self>) {
+        match msg.0 {
+            signal::SignalType::Int | signal::SignalType::Term if self.stopping => {
+                warn!("Stopping without waiting for the deploys in progress.");
+                System::current().stop();
+            }
+            signal::SignalType::Int | signal::SignalType::Term => {
+                self.stopping = true;
+                if self.deploys_in_progress > 0 {
+                    info!(
+                        "Stopping once the {} deploy(s) in progress finish, signal again to stop now.",
+                        self.deploys_in_progress
+                    );
+                }
+                self.stop_once_idle();
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DaemonConfig;
+    use crate::test_server::serve_each;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Instant;
+    use tokio_timer::Delay;
+
+    #[test]
+    fn failed_lookups_are_retried_sooner_until_one_succeeds() {
+        let poll_interval = Duration::from_secs(60);
+        let mut backoff = LookupBackoff::default();
+        // A resolver that is unreachable for a while after boot, then answers
+        let delays: Vec<_> = [false, false, false, false, true, false]
+            .iter()
+            .map(|&succeeded| backoff.next_delay(succeeded, poll_interval).as_secs())
+            .collect();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60, 10]);
+    }
+
+    #[test]
+    fn newer_record_abandons_the_deploy_in_progress() {
+        // The name first resolves to QmFirst, then, from the next poll on, to QmSecond
+        let resolves = AtomicUsize::new(0);
+        let (ipfs_addr, _ipfs_requests) = serve_each(move |_request| {
+            Some(if resolves.fetch_add(1, Ordering::SeqCst) == 0 {
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 24\r\n\r\n{\"Path\":\"/ipfs/QmFirst\"}"
+            } else {
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 25\r\n\r\n{\"Path\":\"/ipfs/QmSecond\"}"
+            })
+        });
+        // The first pull hangs until the deploy of QmFirst is abandoned, everything after succeeds
+        let pulls = AtomicUsize::new(0);
+        let (docker_addr, docker_requests) = serve_each(move |request| {
+            if request.starts_with("POST /v1.39/images/create") {
+                if pulls.fetch_add(1, Ordering::SeqCst) == 0 {
+                    None
+                } else {
+                    Some("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 26\r\n\r\n{\"status\":\"Pulled app:1\"}\n")
+                }
+            } else if request.contains("/tag?") {
+                Some("HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+            } else if request.starts_with("POST /v1.39/containers/create") {
+                Some("HTTP/1.1 201 Created\r\nContent-Type: application/json\r\nContent-Length: 26\r\n\r\n{\"Id\":\"new\",\"Warnings\":[]}")
+            } else if request.starts_with("POST /v1.39/containers/new/start") {
+                Some("HTTP/1.1 204 No Content\r\n\r\n")
+            } else if request.starts_with("GET /v1.39/containers/new/json") {
+                Some("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 67\r\n\r\n{\"Id\":\"new\",\"State\":{\"Running\":true,\"Health\":{\"Status\":\"healthy\"}}}")
+            } else {
+                Some("HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]")
+            }
+        });
+        let ipfs_port = ipfs_addr.rsplit(':').next().unwrap();
+        let config = DaemonConfig::try_from_str(&format!(
+            r#"
+            docker = "tcp://{}"
+            poll_interval_secs = 1
+
+            [ipfs_api_server]
+            host = "127.0.0.1"
+            port = {}
+
+            [[targets]]
+            dns_record_name = "_dnslink.app.example.com"
+            ipns_name = "k51app"
+            [targets.port_map]
+            [targets.deploy_mode]
+            mode = "pull-image"
+            image_ref = "app:1"
+            "#,
+            docker_addr, ipfs_port
+        ))
+        .unwrap();
+        let daemon = LradDaemon { config };
+
+        let sys = System::new("lrad-test");
+        let health = Health::new(daemon.targets());
+        DaemonActor::new(daemon, health).start();
+        Arbiter::spawn(
+            Delay::new(Instant::now() + Duration::from_secs(5)).then(|_| {
+                System::current().stop();
+                Ok(())
+            }),
+        );
+        sys.run();
+
+        let requests = docker_requests.lock().unwrap().clone();
+        let abandoned = requests
+            .iter()
+            .position(|request| {
+                request.starts_with("abandoned POST /v1.39/images/create")
+            })
+            .expect("the first pull should have been hung up on");
+        let tagged = requests
+            .iter()
+            .position(|request| request.contains("tag=QmSecond"))
+            .expect("the newer record should have been deployed");
+        assert!(abandoned < tagged);
+        assert!(!requests.iter().any(|request| request.contains("tag=QmFirst")));
+        assert_eq!(
+            requests
+                .iter()
+                .filter(|request| request.starts_with("POST /v1.39/containers/create"))
+                .count(),
+            1
+        );
+    }
+}
@@ -1,55 +1,526 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::collections::HashMap;
+use std::env;
+use std::fmt;
 use crate::dns::CloudflareConfig;
-use crate::ipfs::IpfsApiServerConfig;
+use crate::docker::DockerConnection;
+use crate::ipfs::{IpfsApiServerConfig, RemotePinConfig};
 use std::fs::File;
 use std::io::{Read, Write};
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
 use git2::Repository;
 
-use crate::error::Result;
+use crate::error::{ErrorKind, Result};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Underscore-separated parts of a key marking its value as a credential, so `api_key` is one but
+/// `gpg_keyring` isn't. Keys that only say where a credential is read from, i.e. `*_env_var` and
+/// `*_file`, are shown as-is.
+const CREDENTIAL_KEY_PARTS: &[&str] = &["key", "token", "password", "secret", "email", "username"];
+
+const REDACTED: &str = "<redacted>";
+
+fn is_credential_key(key: &str) -> bool {
+    !key.ends_with("_env_var")
+        && !key.ends_with("_file")
+        && key.split('_').any(|part| CREDENTIAL_KEY_PARTS.contains(&part))
+}
+
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (key, value) in table.iter_mut() {
+                if is_credential_key(key) && !value.is_table() {
+                    *value = toml::Value::String(String::from(REDACTED));
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        toml::Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Renders the configuration in effect as TOML, with credentials redacted so that it can be
+/// shared when asking for help.
+pub fn render_redacted<T: Serialize>(config: &T) -> Result<String> {
+    let mut value = toml::Value::try_from(config)?;
+    redact(&mut value);
+    Ok(toml::to_string(&value)?)
+}
+
+/// Replaces every `${VAR}` in `s` with the value of the environment variable `VAR`.
+fn interpolate(s: &str) -> Result<String> {
+    let mut interpolated = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(start) = rest.find("${") {
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = &rest[start + 2..end];
+        let value = env::var(name)
+            .map_err(|_| ErrorKind::EnvironmentVariableNotFound(String::from(name)))?;
+        interpolated.push_str(&rest[..start]);
+        interpolated.push_str(&value);
+        rest = &rest[end + 1..];
+    }
+    interpolated.push_str(rest);
+    Ok(interpolated)
+}
+
+fn interpolate_value(value: &mut toml::Value) -> Result<()> {
+    match value {
+        toml::Value::String(s) => *s = interpolate(s)?,
+        toml::Value::Table(table) => {
+            for value in table.values_mut() {
+                interpolate_value(value)?;
+            }
+        }
+        toml::Value::Array(values) => {
+            for value in values.iter_mut() {
+                interpolate_value(value)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Parses a config file, filling in environment variables referenced as `${VAR}` in string values
+/// first.
+fn parse<T: DeserializeOwned>(buf: &[u8]) -> Result<T> {
+    parse_value(toml::from_slice(buf)?)
+}
+
+fn parse_value<T: DeserializeOwned>(mut value: toml::Value) -> Result<T> {
+    interpolate_value(&mut value)?;
+    Ok(value.try_into()?)
+}
+
+/// A problem with a config that parsed fine but would only fail once it is used.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    NoDeployTargets,
+    /// Two targets deploying under the same name would keep replacing each other's container
+    DuplicateDeployTarget(String),
+    EmptyDnsRecordName,
+    /// A dns_record_name that isn't the `_dnslink.` record of a domain, which deploys name their
+    /// containers and images after
+    MissingDnslinkPrefix(String),
+    /// A port map key that isn't written like "80/tcp"
+    InvalidContainerPort(String),
+    /// A port map entry for the container port binding host port 0
+    InvalidHostPort(String),
+    /// An `env` entry that isn't written like "KEY=value"
+    InvalidEnv(String),
+    InvalidRemotePinEndpoint(String),
+    InvalidGatewayUrl(String),
+    InvalidNotifyUrl(String),
+    InvalidDnsTtl(u32),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::NoDeployTargets => write!(f, "there are no targets to deploy"),
+            ConfigError::DuplicateDeployTarget(dns_record_name) => write!(
+                f,
+                "more than one target has dns_record_name {:?}",
+                dns_record_name
+            ),
+            ConfigError::EmptyDnsRecordName => write!(f, "dns_record_name is empty"),
+            ConfigError::MissingDnslinkPrefix(dns_record_name) => write!(
+                f,
+                "dns_record_name {:?} should be the _dnslink. record of a domain, e.g. \"_dnslink.example.com\"",
+                dns_record_name
+            ),
+            ConfigError::InvalidContainerPort(port) => write!(
+                f,
+                "port_map key {:?} should be a port and protocol, e.g. \"80/tcp\"",
+                port
+            ),
+            ConfigError::InvalidHostPort(port) => {
+                write!(f, "port_map entry for {:?} has host_port 0", port)
+            }
+            ConfigError::InvalidEnv(env) => {
+                write!(f, "env entry {:?} should be written like \"KEY=value\"", env)
+            }
+            ConfigError::InvalidRemotePinEndpoint(endpoint) => write!(
+                f,
+                "remote_pin endpoint {:?} should be an http:// or https:// URL",
+                endpoint
+            ),
+            ConfigError::InvalidGatewayUrl(url) => write!(
+                f,
+                "gateway_url {:?} should be an http:// or https:// URL",
+                url
+            ),
+            ConfigError::InvalidDnsTtl(ttl) => write!(
+                f,
+                "dns_record_ttl {} should be 1 for automatic or between 120 and 2147483647",
+                ttl
+            ),
+            ConfigError::InvalidNotifyUrl(url) => write!(
+                f,
+                "notify_url {:?} should be an http:// or https:// URL",
+                url
+            ),
+        }
+    }
+}
+
+/// Whether `port` is written like "80/tcp", the way Docker keys exposed ports.
+fn is_container_port(port: &str) -> bool {
+    let mut parts = port.splitn(2, '/');
+    let number = parts.next().and_then(|number| number.parse::<u16>().ok());
+    let protocol = parts.next();
+    match (number, protocol) {
+        (Some(number), Some(protocol)) => {
+            number != 0 && ["tcp", "udp", "sctp"].contains(&protocol)
+        }
+        _ => false,
+    }
+}
+
+/// Whether `url` is an http:// or https:// URL with a host.
+fn is_http_url(url: &str) -> bool {
+    match url::Url::parse(url) {
+        Ok(url) => ["http", "https"].contains(&url.scheme()) && url.has_host(),
+        Err(_) => false,
+    }
+}
+
+/// Turns the problems found, if any, into a single error.
+fn into_result(errors: Vec<ConfigError>) -> std::result::Result<(), Vec<ConfigError>> {
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
 
 #[derive(Deserialize, Serialize, Default)]
 pub struct CliConfig {
+    /// Where to create temporary directories instead of the system default, e.g. on the SD card
+    pub temp_dir: Option<PathBuf>,
     pub dns_provider: CloudflareConfig,
     pub ipfs_api_server: IpfsApiServerConfig,
+    /// Where `lrad push --pin-remote` pins pushed content
+    pub remote_pin: Option<RemotePinConfig>,
+}
+
+/// Whether the relative `path` names something below the directory it is relative to, rather
+/// than the directory itself or something outside of it.
+fn stays_below(path: &Path) -> bool {
+    let mut depth = 0usize;
+    for component in path.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
+    depth > 0
 }
 
 impl CliConfig {
-    fn config_path(repo: &Repository) -> Result<PathBuf> {
-        let path = if !repo.is_bare() {
-            repo.path()
-                .parent()
-                .expect(".git should always have a parent folder in a non-bare repo")
-        } else {
-            repo.path()
-        };
-        let mut path = PathBuf::from(path);
-        path.push(Path::new(".lrad.toml"));
-        Ok(path)
+    /// `name`, `.lrad.toml` unless given, relative to the root of the working tree, or to the repo
+    /// itself if it is bare. An absolute `name` is taken as is, but a relative one has to stay
+    /// inside the repo.
+    fn config_path(repo: &Repository, name: Option<&Path>) -> Result<PathBuf> {
+        let root = repo.workdir().unwrap_or_else(|| repo.path());
+        let name = name.unwrap_or_else(|| Path::new(CLI_CONFIG_NAME));
+        if name.is_absolute() {
+            return Ok(name.to_path_buf());
+        }
+        if !stays_below(name) {
+            return Err(ErrorKind::ConfigPathOutsideRepo(name.to_path_buf()).into());
+        }
+        Ok(root.join(name))
+    }
+
+    /// Reads the config of `repo`, see `config_path` for where `name` puts it.
+    pub fn try_from(repo: &Repository, name: Option<&Path>) -> Result<Self> {
+        Self::try_from_path(&Self::config_path(repo, name)?)
     }
-    pub fn try_from(repo: &Repository) -> Result<Self> {
-        let mut file = File::open(Self::config_path(repo)?)?;
+
+    /// Reads the config at `path` rather than the one in the repo, e.g. one given with `-c`.
+    pub fn try_from_path(path: &Path) -> Result<Self> {
+        let mut file = File::open(path)?;
         let metadata = file.metadata()?;
         let mut buf = Vec::with_capacity(metadata.len() as usize);
         let _bytes_read = file.read_to_end(&mut buf)?;
-        toml::from_slice(buf.as_slice()).map_err(|err| err.into())
+        let config: Self = parse(&buf)?;
+        config.validate().map_err(ErrorKind::InvalidConfig)?;
+        Ok(config)
+    }
+
+    /// Checks for everything wrong with the config at once, rather than failing on the first
+    /// problem halfway through a push.
+    pub fn validate(&self) -> std::result::Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        if self.dns_provider.dns_record_name().is_empty() {
+            errors.push(ConfigError::EmptyDnsRecordName);
+        }
+        let dns_record_ttl = self.dns_provider.dns_record_ttl();
+        if !crate::dns::is_valid_ttl(dns_record_ttl) {
+            errors.push(ConfigError::InvalidDnsTtl(dns_record_ttl));
+        }
+        if let Some(remote_pin) = &self.remote_pin {
+            if !is_http_url(&remote_pin.endpoint) {
+                errors.push(ConfigError::InvalidRemotePinEndpoint(
+                    remote_pin.endpoint.clone(),
+                ));
+            }
+        }
+        into_result(errors)
     }
 
-    pub fn write(&self, repo: &Repository) -> Result<()> {
+    pub fn write(&self, repo: &Repository, name: Option<&Path>) -> Result<()> {
         let config_json_str = toml::to_string(self).unwrap();
-        let mut file = File::create(Self::config_path(repo)?)?;
+        let config_path = Self::config_path(repo, name)?;
+        if let Some(parent) = config_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(config_path)?;
         file.write(config_json_str.as_bytes())?;
         Ok(())
     }
 }
 
+pub const CLI_CONFIG_NAME: &str = ".lrad.toml";
+
+pub const DAEMON_CONFIG_PATH: &str = "/etc/lrad/lrad-daemon.toml";
+
+/// Holds the daemon config itself, taking the place of the file at `DAEMON_CONFIG_PATH`
+pub const DAEMON_CONFIG_ENV_VAR: &str = "LRAD_DAEMON_CONFIG";
+
 #[derive(Deserialize, Serialize)]
 pub struct DaemonConfig {
-    /// e.g. git.lrad.io
+    /// Where to create temporary directories instead of the system default, e.g. on the SD card
+    pub temp_dir: Option<PathBuf>,
+    /// The Docker daemon to deploy with, e.g. "unix:///run/user/1000/docker.sock" for rootless
+    /// Docker or "tcp://192.168.1.2:2375"
+    #[serde(default)]
+    pub docker: DockerConnection,
+    /// Deploy by running this Docker-compatible CLI, e.g. "nerdctl" or "podman", instead of
+    /// talking to the Docker API
+    pub runtime_command: Option<String>,
+    /// Gzip the build context before sending it, which pays off over a TCP connection. Defaults
+    /// to compressing only when `docker` is a TCP address.
+    pub compress_build_context: Option<bool>,
+    /// How many times a DNS lookup that times out or fails with SERVFAIL is attempted before the
+    /// poll is given up on
+    pub dns_lookup_attempts: Option<u32>,
+    /// Delay before the first retry of a DNS lookup, doubled for each further retry
+    pub dns_retry_base_delay_ms: Option<u64>,
+    /// How long to wait between lookups of a target's record, 300 if unset
+    pub poll_interval_secs: Option<u64>,
+    /// Randomly stretches or shrinks each poll interval by up to this percentage, so a fleet of
+    /// devices doesn't hit the resolver all at once
+    pub poll_jitter_percent: Option<u8>,
+    /// How often to re-verify that the deployed content is intact in the local IPFS store
+    pub integrity_check_interval_secs: Option<u64>,
+    /// Upper bound on Docker requests issued at once, e.g. when removing old containers
+    pub max_concurrent_docker_ops: Option<usize>,
+    /// How many times a Docker request that fails transiently is attempted before giving up
+    pub docker_retry_attempts: Option<u32>,
+    /// Delay before the first retry of a Docker request, doubled for each further retry
+    pub docker_retry_base_delay_ms: Option<u64>,
+    /// Log how long each deploy stage took, e.g. "build took 42.000s", at info instead of debug
+    /// level, to catch builds or starts getting slower on the device
+    #[serde(default)]
+    pub log_stage_durations: bool,
+    /// The IPFS gateway deployments are cloned through, http://localhost:8080 if unset
+    pub gateway_url: Option<String>,
+    /// Where to POST a JSON report of each finished deploy, successful or not
+    pub notify_url: Option<String>,
+    /// Identifies this device in deploy reports, the hostname if unset
+    pub device_id: Option<String>,
+    /// Pin each deployed CID on the local IPFS node, so that redeploys and peers are served from
+    /// it, unpinning it again once its image is pruned, see `keep_images`
+    #[serde(default)]
+    pub pin_deployments: bool,
+    /// Where to serve /healthz, /readyz and /status for monitoring, e.g. "127.0.0.1:9090", not
+    /// served at all if unset
+    pub status_addr: Option<SocketAddr>,
+    #[serde(default)]
+    pub ipfs_api_server: IpfsApiServerConfig,
+    /// Only deploy repos whose HEAD commit is signed by one of these keys, if set
+    pub trusted_signers: Option<TrustedSigners>,
+    /// The apps this daemon deploys, each followed and deployed on its own
+    pub targets: Vec<DeployTarget>,
+}
+
+/// Keys of a deploy target, which a config from before there could be several of them has at
+/// the top level.
+const DEPLOY_TARGET_KEYS: &[&str] = &[
+    "dns_record_name",
+    "ipns_name",
+    "replace",
+    "health_check_timeout_secs",
+    "restart_policy",
+    "memory_bytes",
+    "nano_cpus",
+    "env",
+    "binds",
+    "keep_images",
+    "port_map",
+    "deploy_mode",
+];
+
+/// Moves the keys of a single top-level deploy target into `targets`.
+fn upgrade_single_target(value: &mut toml::Value) {
+    let table = match value {
+        toml::Value::Table(table) => table,
+        _ => return,
+    };
+    if table.contains_key("targets") || !table.contains_key("dns_record_name") {
+        return;
+    }
+    let mut target = toml::value::Table::new();
+    for key in DEPLOY_TARGET_KEYS {
+        if let Some(value) = table.remove(*key) {
+            target.insert(String::from(*key), value);
+        }
+    }
+    table.insert(
+        String::from("targets"),
+        toml::Value::Array(vec![toml::Value::Table(target)]),
+    );
+}
+
+/// Keys trusted to sign the commits the daemon deploys, by the kind of signature.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct TrustedSigners {
+    /// A keyring of trusted OpenPGP keys as read by gpgv, e.g. written by
+    /// `gpg --export <key id> > /etc/lrad/trusted.gpg`
+    pub gpg_keyring: Option<PathBuf>,
+    /// An allowed signers file as read by `ssh-keygen -Y verify`, see gpg.ssh.allowedSignersFile
+    /// in git-config(1)
+    pub ssh_allowed_signers: Option<PathBuf>,
+}
+
+/// An app the daemon follows and deploys.
+#[derive(Deserialize, Serialize)]
+pub struct DeployTarget {
+    /// e.g. _dnslink.git.lrad.io, which also names the deployed container and image
     pub dns_record_name: String,
-    pub port_map: HashMap<String, Vec<PortBinding>>
+    /// Follow this IPNS name, e.g. one published with `lrad push --ipns`, by resolving it through
+    /// the IPFS node instead of looking up the DNS record. `dns_record_name` then only names the
+    /// deployment.
+    pub ipns_name: Option<String>,
+    /// Start the new container alongside the old one and only swap it in once it stays up
+    #[serde(default)]
+    pub replace: bool,
+    /// How long a new container gets to become healthy before the deploy is abandoned
+    pub health_check_timeout_secs: Option<u64>,
+    /// Whether Docker restarts the deployed container, e.g. after the device reboots
+    #[serde(default)]
+    pub restart_policy: RestartPolicy,
+    /// Memory limit of the deployed container, so that it can't starve the OS and the daemon
+    pub memory_bytes: Option<i64>,
+    /// CPU limit of the deployed container in billionths of a CPU, e.g. 500000000 for half a core
+    pub nano_cpus: Option<i64>,
+    /// Environment variables passed to the deployed container, e.g. "KEY=value"
+    #[serde(default)]
+    pub env: Vec<String>,
+    /// Volumes mounted into the deployed container, e.g. "/srv/app-data:/data"
+    #[serde(default)]
+    pub binds: Vec<String>,
+    /// How many of the most recent `<name>:<cid>` images to keep around for rolling back
+    pub keep_images: Option<usize>,
+    pub port_map: HashMap<String, Vec<PortBinding>>,
+    #[serde(default)]
+    pub deploy_mode: DeployMode,
+}
+
+impl DeployTarget {
+    fn template() -> Self {
+        Self {
+            dns_record_name: String::from("_dnslink.example.com"),
+            ipns_name: None,
+            replace: false,
+            health_check_timeout_secs: None,
+            restart_policy: RestartPolicy::default(),
+            memory_bytes: None,
+            nano_cpus: None,
+            env: Vec::new(),
+            binds: Vec::new(),
+            keep_images: None,
+            port_map: HashMap::new(),
+            deploy_mode: DeployMode::default(),
+        }
+    }
+
+    fn validate(&self, errors: &mut Vec<ConfigError>) {
+        if self.dns_record_name.is_empty() {
+            errors.push(ConfigError::EmptyDnsRecordName);
+        } else if !self.dns_record_name.starts_with("_dnslink.")
+            || self.dns_record_name == "_dnslink."
+        {
+            errors.push(ConfigError::MissingDnslinkPrefix(
+                self.dns_record_name.clone(),
+            ));
+        }
+        let mut container_ports: Vec<&String> = self.port_map.keys().collect();
+        container_ports.sort();
+        for container_port in container_ports {
+            if !is_container_port(container_port) {
+                errors.push(ConfigError::InvalidContainerPort(container_port.clone()));
+            }
+            if self.port_map[container_port]
+                .iter()
+                .any(|binding| binding.host_port == 0)
+            {
+                errors.push(ConfigError::InvalidHostPort(container_port.clone()));
+            }
+        }
+        for env in &self.env {
+            match env.find('=') {
+                Some(equals) if equals > 0 => {}
+                _ => errors.push(ConfigError::InvalidEnv(env.clone())),
+            }
+        }
+    }
+}
+
+/// Where the image that gets run comes from.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "mode", rename_all = "kebab-case")]
+pub enum DeployMode {
+    /// Build the image on the device from the published repo
+    BuildFromRepo,
+    /// Pull an image built elsewhere, e.g. in CI. Without an `image_ref`, it is read from the
+    /// `.lrad-image` file of the published repo.
+    PullImage { image_ref: Option<String> },
+}
+
+impl Default for DeployMode {
+    fn default() -> Self {
+        DeployMode::BuildFromRepo
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    No,
+    OnFailure,
+    Always,
+    UnlessStopped,
+}
+
+impl Default for RestartPolicy {
+    /// Deployments are long-running services on unattended devices
+    fn default() -> Self {
+        RestartPolicy::UnlessStopped
+    }
 }
 
 #[derive(Deserialize, Serialize)]
@@ -59,11 +530,360 @@ pub struct PortBinding {
 }
 
 impl DaemonConfig {
+    /// A starting point for `lrad init --daemon`, to be edited by the user.
+    pub fn template() -> Self {
+        Self {
+            temp_dir: None,
+            docker: DockerConnection::default(),
+            runtime_command: None,
+            compress_build_context: None,
+            dns_lookup_attempts: None,
+            dns_retry_base_delay_ms: None,
+            poll_interval_secs: None,
+            poll_jitter_percent: None,
+            integrity_check_interval_secs: None,
+            max_concurrent_docker_ops: None,
+            docker_retry_attempts: None,
+            docker_retry_base_delay_ms: None,
+            log_stage_durations: false,
+            gateway_url: None,
+            notify_url: None,
+            device_id: None,
+            pin_deployments: false,
+            status_addr: None,
+            ipfs_api_server: IpfsApiServerConfig::default(),
+            trusted_signers: None,
+            targets: vec![DeployTarget::template()],
+        }
+    }
+
     pub fn try_from(path: &Path) -> Result<Self> {
         let mut file = File::open(path)?;
         let metadata = file.metadata()?;
         let mut buf = Vec::with_capacity(metadata.len() as usize);
         let _bytes_read = file.read_to_end(&mut buf)?;
-        toml::from_slice(buf.as_slice()).map_err(|err| err.into())
+        Self::try_from_slice(&buf)
+    }
+
+    /// Reads the config from TOML at hand rather than a file, e.g. piped in on stdin.
+    pub fn try_from_str(s: &str) -> Result<Self> {
+        Self::try_from_slice(s.as_bytes())
+    }
+
+    /// Reads the config from the TOML held by the environment variable `var_name`, for running
+    /// the daemon in a container without mounting a config file.
+    pub fn from_env(var_name: &str) -> Result<Self> {
+        let s = env::var(var_name)
+            .map_err(|_| ErrorKind::EnvironmentVariableNotFound(String::from(var_name)))?;
+        Self::try_from_str(&s)
+    }
+
+    fn try_from_slice(buf: &[u8]) -> Result<Self> {
+        let config = Self::from_slice(buf)?;
+        config.validate().map_err(ErrorKind::InvalidConfig)?;
+        Ok(config)
+    }
+
+    fn from_slice(buf: &[u8]) -> Result<Self> {
+        let mut value: toml::Value = toml::from_slice(buf)?;
+        upgrade_single_target(&mut value);
+        parse_value(value)
+    }
+
+    /// Checks for everything wrong with the config at once, rather than failing on the first
+    /// problem deep inside a deploy.
+    pub fn validate(&self) -> std::result::Result<(), Vec<ConfigError>> {
+        let mut errors = Vec::new();
+        if let Some(gateway_url) = &self.gateway_url {
+            if !is_http_url(gateway_url) {
+                errors.push(ConfigError::InvalidGatewayUrl(gateway_url.clone()));
+            }
+        }
+        if let Some(notify_url) = &self.notify_url {
+            if !is_http_url(notify_url) {
+                errors.push(ConfigError::InvalidNotifyUrl(notify_url.clone()));
+            }
+        }
+        if self.targets.is_empty() {
+            errors.push(ConfigError::NoDeployTargets);
+        }
+        for (i, target) in self.targets.iter().enumerate() {
+            target.validate(&mut errors);
+            if self.targets[..i]
+                .iter()
+                .any(|other| other.dns_record_name == target.dns_record_name)
+            {
+                errors.push(ConfigError::DuplicateDeployTarget(
+                    target.dns_record_name.clone(),
+                ));
+            }
+        }
+        into_result(errors)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let config_toml_str = toml::to_string(self)?;
+        let mut file = File::create(path)?;
+        file.write(config_toml_str.as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cli_config_is_at_the_root_of_the_repo_it_belongs_to() {
+        let outer = tempfile::tempdir().unwrap();
+        Repository::init(outer.path()).unwrap();
+        let inner_path = outer.path().join("vendor").join("app");
+        Repository::init(&inner_path).unwrap();
+        std::fs::create_dir(inner_path.join("src")).unwrap();
+        let inner = Repository::discover(inner_path.join("src")).unwrap();
+        let config_path = CliConfig::config_path(&inner, None).unwrap();
+        assert_eq!(config_path.file_name().unwrap(), ".lrad.toml");
+        assert_eq!(
+            config_path.parent().unwrap().canonicalize().unwrap(),
+            inner_path.canonicalize().unwrap()
+        );
+
+        let bare_path = outer.path().join("app.git");
+        let bare = Repository::init_bare(&bare_path).unwrap();
+        assert_eq!(
+            CliConfig::config_path(&bare, None).unwrap(),
+            bare.path().join(".lrad.toml")
+        );
+    }
+
+    #[test]
+    fn custom_cli_config_name_is_relative_to_the_repo() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let root = repo.workdir().unwrap().to_path_buf();
+        assert_eq!(
+            CliConfig::config_path(&repo, Some(Path::new("staging.toml"))).unwrap(),
+            root.join("staging.toml")
+        );
+        match CliConfig::config_path(&repo, Some(Path::new("services/../../.lrad.toml"))) {
+            Err(box ErrorKind::ConfigPathOutsideRepo(path)) => {
+                assert_eq!(path, Path::new("services/../../.lrad.toml"))
+            }
+            other => panic!("expected ConfigPathOutsideRepo, got {:?}", other),
+        }
+        assert!(CliConfig::config_path(&repo, Some(Path::new("."))).is_err());
+    }
+
+    #[test]
+    fn cli_config_can_live_in_a_subdirectory() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let name = Path::new("services/api/.lrad.toml");
+        CliConfig::default().write(&repo, Some(name)).unwrap();
+        assert!(repo.workdir().unwrap().join(name).is_file());
+    }
+
+    #[test]
+    fn environment_variables_are_interpolated() {
+        env::set_var("LRAD_TEST_RECORD", "app.example.com");
+        env::set_var("LRAD_TEST_DATA_DIR", "/srv/app");
+        let config = DaemonConfig::from_slice(
+            br#"
+            dns_record_name = "_dnslink.${LRAD_TEST_RECORD}"
+            binds = ["${LRAD_TEST_DATA_DIR}:/data", "/tmp:/tmp"]
+            port_map = {}
+            "#,
+        )
+        .unwrap();
+        let target = &config.targets[0];
+        assert_eq!(target.dns_record_name, "_dnslink.app.example.com");
+        assert_eq!(target.binds, vec!["/srv/app:/data", "/tmp:/tmp"]);
+    }
+
+    #[test]
+    fn every_config_problem_is_reported() {
+        let config = DaemonConfig::from_slice(
+            br#"
+            dns_record_name = ""
+            env = ["KEY=value", "NO_EQUALS"]
+            gateway_url = "localhost:8080"
+
+            [port_map]
+            "80/tcp" = [{ host_port = 8080 }]
+            "http" = [{ host_port = 0 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ConfigError::InvalidGatewayUrl(String::from("localhost:8080")),
+                ConfigError::EmptyDnsRecordName,
+                ConfigError::InvalidContainerPort(String::from("http")),
+                ConfigError::InvalidHostPort(String::from("http")),
+                ConfigError::InvalidEnv(String::from("NO_EQUALS")),
+            ])
+        );
+        assert_eq!(DaemonConfig::template().validate(), Ok(()));
+    }
+
+    #[test]
+    fn several_targets_are_read() {
+        let config = DaemonConfig::from_slice(
+            br#"
+            poll_jitter_percent = 10
+
+            [[targets]]
+            dns_record_name = "_dnslink.app.example.com"
+            [targets.port_map]
+            "80/tcp" = [{ host_port = 8080 }]
+
+            [[targets]]
+            dns_record_name = "_dnslink.api.example.com"
+            env = ["KEY=value"]
+            [targets.port_map]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.poll_jitter_percent, Some(10));
+        let names: Vec<&str> = config
+            .targets
+            .iter()
+            .map(|target| target.dns_record_name.as_str())
+            .collect();
+        assert_eq!(names, vec!["_dnslink.app.example.com", "_dnslink.api.example.com"]);
+        assert_eq!(config.targets[1].env, vec!["KEY=value"]);
+        assert_eq!(config.validate(), Ok(()));
+    }
+
+    #[test]
+    fn targets_need_a_dnslink_record_name() {
+        let config = DaemonConfig::from_slice(
+            br#"
+            [[targets]]
+            dns_record_name = "app.example.com"
+            [targets.port_map]
+
+            [[targets]]
+            dns_record_name = "_dnslink."
+            [targets.port_map]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.validate(),
+            Err(vec![
+                ConfigError::MissingDnslinkPrefix(String::from("app.example.com")),
+                ConfigError::MissingDnslinkPrefix(String::from("_dnslink.")),
+            ])
+        );
+    }
+
+    #[test]
+    fn daemon_config_is_read_from_the_environment() {
+        env::set_var(
+            "LRAD_TEST_DAEMON_CONFIG",
+            r#"
+            [[targets]]
+            dns_record_name = "_dnslink.app.example.com"
+            [targets.port_map]
+            "#,
+        );
+        let config = DaemonConfig::from_env("LRAD_TEST_DAEMON_CONFIG").unwrap();
+        assert_eq!(config.targets[0].dns_record_name, "_dnslink.app.example.com");
+        match DaemonConfig::try_from_str("targets = []") {
+            Err(box ErrorKind::InvalidConfig(errors)) => {
+                assert_eq!(errors, vec![ConfigError::NoDeployTargets])
+            }
+            _ => panic!("expected an InvalidConfig error"),
+        }
+        match DaemonConfig::from_env("LRAD_TEST_UNSET_DAEMON_CONFIG") {
+            Err(box ErrorKind::EnvironmentVariableNotFound(var)) => {
+                assert_eq!(var, "LRAD_TEST_UNSET_DAEMON_CONFIG")
+            }
+            _ => panic!("expected an EnvironmentVariableNotFound error"),
+        }
+    }
+
+    #[test]
+    fn single_target_config_still_reads() {
+        let config = DaemonConfig::from_slice(
+            br#"
+            dns_record_name = "_dnslink.app.example.com"
+            poll_jitter_percent = 10
+            replace = true
+
+            [port_map]
+            "80/tcp" = [{ host_port = 8080 }]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.poll_jitter_percent, Some(10));
+        assert_eq!(config.targets.len(), 1);
+        assert!(config.targets[0].replace);
+        assert_eq!(config.targets[0].port_map["80/tcp"][0].host_port, 8080);
+    }
+
+    #[test]
+    fn unset_environment_variable_is_reported() {
+        match interpolate("${LRAD_TEST_UNSET_VAR}") {
+            Err(box ErrorKind::EnvironmentVariableNotFound(name)) => {
+                assert_eq!(name, "LRAD_TEST_UNSET_VAR")
+            }
+            _ => panic!("expected an EnvironmentVariableNotFound error"),
+        }
+        assert_eq!(interpolate("cost: $5").unwrap(), "cost: $5");
+    }
+
+    #[test]
+    fn rendered_config_shows_settings_and_hides_credentials() {
+        let config: CliConfig = toml::from_str(
+            r#"
+            temp_dir = "/mnt/sd/tmp"
+
+            [dns_provider]
+            email_env_var = "CF_EMAIL"
+            api_key_env_var = "CF_API_KEY"
+            zone_id_env_var = "CF_ZONE_ID"
+            dns_record_id_env_var = "CF_DNS_RECORD_ID"
+            dns_record_name = "_dnslink.app.example.com"
+
+            [ipfs_api_server]
+            host = "ipfs.example.com"
+            port = 443
+            scheme = "https"
+
+            [ipfs_api_server.auth]
+            type = "basic"
+            username = "alice"
+            password_env_var = "IPFS_PASSWORD"
+            "#,
+        )
+        .unwrap();
+        let rendered = render_redacted(&config).unwrap();
+        assert!(rendered.contains("temp_dir = \"/mnt/sd/tmp\""));
+        assert!(rendered.contains("host = \"ipfs.example.com\""));
+        assert!(rendered.contains("api_key_env_var = \"CF_API_KEY\""));
+        assert!(rendered.contains("password_env_var = \"IPFS_PASSWORD\""));
+        assert!(rendered.contains("username = \"<redacted>\""));
+        assert!(!rendered.contains("alice"));
+    }
+
+    #[test]
+    fn rendered_config_shows_where_trusted_keys_are() {
+        let config = DaemonConfig::from_slice(
+            br#"
+            [trusted_signers]
+            gpg_keyring = "/etc/lrad/trusted.gpg"
+
+            [[targets]]
+            dns_record_name = "_dnslink.app.example.com"
+            [targets.port_map]
+            "#,
+        )
+        .unwrap();
+        let rendered = render_redacted(&config).unwrap();
+        assert!(rendered.contains("gpg_keyring = \"/etc/lrad/trusted.gpg\""));
+        assert!(!rendered.contains(REDACTED));
     }
 }
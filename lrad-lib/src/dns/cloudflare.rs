@@ -1,7 +1,9 @@
-use crate::dns::DnsRecordPutter;
-use crate::error::{BoxFuture, Error, ErrorKind};
+use crate::dns::{DnsLink, DnsRecordPutter};
+use crate::error::{BoxFuture, Error, ErrorKind, Result};
 
 use std::env;
+use std::fs;
+use std::path::PathBuf;
 use std::ops::Range;
 use std::sync::mpsc;
 
@@ -61,58 +63,81 @@ pub struct CloudflareConfig {
     api_key_env_var: CloudflareApiKeyEnvVar,
     zone_id_env_var: CloudflareZoneIdEnvVar,
     dns_record_id_env_var: CloudflareDnsRecordIdEnvVar,
+    /// Secret files take precedence over their environment variable, e.g. /run/secrets/cf_api_key
+    email_file: Option<PathBuf>,
+    api_key_file: Option<PathBuf>,
+    zone_id_file: Option<PathBuf>,
+    dns_record_id_file: Option<PathBuf>,
     dns_record_name: String,
     dns_record_ttl: Option<CloudflareDnsRecordTTL>,
 }
 
+impl CloudflareConfig {
+    pub fn dns_record_name(&self) -> &str {
+        &self.dns_record_name
+    }
+
+    /// The configured TTL, which `is_valid_ttl` should hold for.
+    pub fn dns_record_ttl(&self) -> u32 {
+        self.dns_record_ttl.unwrap_or_default().0
+    }
+}
+
+/// Whether Cloudflare accepts `ttl`, i.e. it is 1 for automatic or within `VALID_TTL_RANGE`.
+pub fn is_valid_ttl(ttl: u32) -> bool {
+    ttl == 1 || VALID_TTL_RANGE.contains(&ttl)
+}
+
+/// Reads a secret from `file` if one is configured, otherwise from the environment variable.
+fn resolve_secret(file: &Option<PathBuf>, env_var: &str) -> Result<String> {
+    match file {
+        Some(file) => fs::read_to_string(file)
+            .map(|secret| String::from(secret.trim_end()))
+            .map_err(|err| ErrorKind::SecretFileUnreadable(file.clone(), err).into()),
+        None => env::vars()
+            .find(|x| x.0 == env_var)
+            .map(|x| x.1)
+            .ok_or_else(|| ErrorKind::EnvironmentVariableNotFound(String::from(env_var)).into()),
+    }
+}
+
 impl DnsRecordPutter for CloudflareConfig {
     fn try_put_txt_record(&self, ipfs_cid: String) -> BoxFuture<bool> {
-        debug!("Reading environment variables");
-        let cf_email_address = env::vars()
-            .find(|x| x.0 == self.email_env_var.0)
-            .ok_or_else(|| ErrorKind::EnvironmentVariableNotFound(self.email_env_var.0.clone()));
+        debug!("Reading secrets");
+        let cf_email_address = resolve_secret(&self.email_file, &self.email_env_var.0);
         if cf_email_address.is_err() {
-            return Box::new(future::err(cf_email_address.unwrap_err().into()));
+            return Box::new(future::err(cf_email_address.unwrap_err()));
         }
-        let cf_api_key = env::vars()
-            .find(|x| x.0 == self.api_key_env_var.0)
-            .ok_or_else(|| ErrorKind::EnvironmentVariableNotFound(self.api_key_env_var.0.clone()));
+        let cf_api_key = resolve_secret(&self.api_key_file, &self.api_key_env_var.0);
         if cf_api_key.is_err() {
-            return Box::new(future::err(cf_api_key.unwrap_err().into()));
+            return Box::new(future::err(cf_api_key.unwrap_err()));
         }
-        let zone_id = env::vars()
-            .find(|x| x.0 == self.zone_id_env_var.0)
-            .ok_or_else(|| ErrorKind::EnvironmentVariableNotFound(self.zone_id_env_var.0.clone()));
+        let zone_id = resolve_secret(&self.zone_id_file, &self.zone_id_env_var.0);
         if zone_id.is_err() {
-            return Box::new(future::err(zone_id.unwrap_err().into()));
+            return Box::new(future::err(zone_id.unwrap_err()));
         }
-        let dns_record_id = env::vars()
-            .find(|x| x.0 == self.dns_record_id_env_var.0)
-            .ok_or_else(|| {
-                ErrorKind::EnvironmentVariableNotFound(self.dns_record_id_env_var.0.clone())
-            });
+        let dns_record_id = resolve_secret(&self.dns_record_id_file, &self.dns_record_id_env_var.0);
         if dns_record_id.is_err() {
-            return Box::new(future::err(dns_record_id.unwrap_err().into()));
+            return Box::new(future::err(dns_record_id.unwrap_err()));
         }
         let dns_record_name = self.dns_record_name.clone();
-        let dns_record_ttl = self.dns_record_ttl.unwrap_or_default().0;
-        if dns_record_ttl != 1 && !VALID_TTL_RANGE.contains(&dns_record_ttl) {
-            // TODO: Actually handle this
-            panic!("Invalid TTL: {}", dns_record_ttl);
+        let dns_record_ttl = self.dns_record_ttl();
+        if !is_valid_ttl(dns_record_ttl) {
+            return Box::new(future::err(ErrorKind::InvalidDnsTtl(dns_record_ttl).into()));
         }
         debug!("Building actix-web request");
         let url = format!(
             "https://api.cloudflare.com/client/v4/zones/{}/dns_records/{}",
-            zone_id.unwrap().1,
-            dns_record_id.unwrap().1
+            zone_id.unwrap(),
+            dns_record_id.unwrap()
         );
         let record =
             DnsLinkTxtRecord::new(dns_record_name.clone(), ipfs_cid.clone(), dns_record_ttl);
         let (tx, rx) = mpsc::channel();
         actix::run(|| {
             client::put(url)
-                .header("X-Auth-Email", cf_email_address.unwrap().1)
-                .header("X-Auth-Key", cf_api_key.unwrap().1)
+                .header("X-Auth-Email", cf_email_address.unwrap())
+                .header("X-Auth-Key", cf_api_key.unwrap())
                 .content_type("application/json")
                 .json(record)
                 .map(|x| {
@@ -128,7 +153,7 @@ impl DnsRecordPutter for CloudflareConfig {
                 })
                 .and_then(move |response: DnsRecordResponse| {
                     debug!("Moving CF put response...");
-                    Ok(response.success)
+                    response.into_result()
                 })
                 .then(move |res| {
                     actix::System::current().stop();
@@ -156,13 +181,81 @@ impl DnsLinkTxtRecord {
         DnsLinkTxtRecord {
             record_type: "TXT",
             name,
-            content: format!("dnslink=/ipfs/{}", ipfs_cid),
+            content: DnsLink::Ipfs(ipfs_cid).to_string(),
             ttl,
         }
     }
 }
 
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct CloudflareApiErrorMessage {
+    pub code: u32,
+    pub message: String,
+}
+
 #[derive(Deserialize, Clone)]
 struct DnsRecordResponse {
     success: bool,
+    #[serde(default)]
+    errors: Vec<CloudflareApiErrorMessage>,
+}
+
+impl DnsRecordResponse {
+    fn into_result(self) -> Result<bool> {
+        if self.success {
+            Ok(true)
+        } else {
+            Err(ErrorKind::CloudflareApiError(self.errors).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn cloudflare_errors_are_surfaced() {
+        let response: DnsRecordResponse = serde_json::from_str(
+            r#"{"result":null,"success":false,"errors":[{"code":81057,"message":"Record already exists."}],"messages":[]}"#,
+        )
+        .unwrap();
+        match response.into_result() {
+            Err(box ErrorKind::CloudflareApiError(errors)) => assert_eq!(
+                errors,
+                vec![CloudflareApiErrorMessage {
+                    code: 81057,
+                    message: String::from("Record already exists."),
+                }]
+            ),
+            _ => panic!("expected a CloudflareApiError"),
+        }
+    }
+
+    #[test]
+    fn ttl_is_automatic_or_in_range() {
+        assert!(is_valid_ttl(1));
+        assert!(is_valid_ttl(120));
+        assert!(!is_valid_ttl(0));
+        assert!(!is_valid_ttl(60));
+        assert!(!is_valid_ttl(2147483648));
+    }
+
+    #[test]
+    fn secret_is_read_from_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        writeln!(file, "hunter2").unwrap();
+        let secret = resolve_secret(&Some(file.path().to_path_buf()), "LRAD_UNUSED_ENV_VAR");
+        assert_eq!(secret.unwrap(), "hunter2");
+    }
+
+    #[test]
+    fn missing_secret_file_is_reported() {
+        let path = PathBuf::from("/nonexistent/lrad/cf_api_key");
+        match resolve_secret(&Some(path.clone()), "LRAD_UNUSED_ENV_VAR") {
+            Err(box ErrorKind::SecretFileUnreadable(err_path, _)) => assert_eq!(err_path, path),
+            _ => panic!("expected a SecretFileUnreadable error"),
+        }
+    }
 }
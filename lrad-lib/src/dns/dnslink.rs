@@ -0,0 +1,82 @@
+use std::fmt;
+
+const DNSLINK_PREFIX: &str = "dnslink=";
+
+/// What a DNSLink TXT record points at, e.g. "dnslink=/ipfs/<cid>".
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnsLink {
+    /// Immutable content, by CID
+    Ipfs(String),
+    /// Another mutable name, e.g. a DNS name with its own DNSLink or a node key's IPNS name
+    Ipns(String),
+}
+
+impl DnsLink {
+    /// Parses the content of a TXT record, returning `None` if it isn't a DNSLink.
+    pub fn parse(txt: &str) -> Option<Self> {
+        let path = txt.trim();
+        if !path.starts_with(DNSLINK_PREFIX) {
+            return None;
+        }
+        let mut parts = path[DNSLINK_PREFIX.len()..].splitn(3, '/');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(""), Some("ipfs"), Some(cid)) if !cid.is_empty() => {
+                Some(DnsLink::Ipfs(String::from(cid)))
+            }
+            (Some(""), Some("ipns"), Some(name)) if !name.is_empty() => {
+                Some(DnsLink::Ipns(String::from(name)))
+            }
+            _ => None,
+        }
+    }
+
+    /// The CID of the content, if the link points at immutable content.
+    pub fn into_cid(self) -> Option<String> {
+        match self {
+            DnsLink::Ipfs(cid) => Some(cid),
+            DnsLink::Ipns(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for DnsLink {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DnsLink::Ipfs(cid) => write!(f, "{}/ipfs/{}", DNSLINK_PREFIX, cid),
+            DnsLink::Ipns(name) => write!(f, "{}/ipns/{}", DNSLINK_PREFIX, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipfs_link_round_trips() {
+        let txt = "dnslink=/ipfs/QmWATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u";
+        let dnslink = DnsLink::parse(txt).unwrap();
+        assert_eq!(
+            dnslink,
+            DnsLink::Ipfs(String::from("QmWATWQ7fVPP2EFGu71UkfnqhYXDYH566qy47CnJDgvs8u"))
+        );
+        assert_eq!(dnslink.to_string(), txt);
+    }
+
+    #[test]
+    fn ipns_link_round_trips() {
+        let txt = "dnslink=/ipns/app.example.com";
+        let dnslink = DnsLink::parse(txt).unwrap();
+        assert_eq!(dnslink, DnsLink::Ipns(String::from("app.example.com")));
+        assert_eq!(dnslink.to_string(), txt);
+        assert_eq!(dnslink.into_cid(), None);
+    }
+
+    #[test]
+    fn other_txt_records_are_not_links() {
+        assert_eq!(DnsLink::parse("v=spf1 -all"), None);
+        assert_eq!(DnsLink::parse("dnslink=/ipfs/"), None);
+        assert_eq!(DnsLink::parse("dnslink=/swarm/QmTest"), None);
+        assert_eq!(DnsLink::parse("dnslink=ipfs/QmTest"), None);
+    }
+}
@@ -1,16 +1,22 @@
 mod cloudflare;
+mod dnslink;
 
 use ::actix::prelude::*;
 use futures::prelude::*;
 use trust_dns_proto::rr::{RData, RecordType};
 use trust_dns_resolver::{
     config::{ResolverConfig, ResolverOpts},
+    error::ResolveErrorKind,
     AsyncResolver,
 };
 
-use crate::error::Error;
+use std::time::Duration;
+
+use crate::error::{Error, ErrorKind};
+use crate::retry::retry;
 
 pub use self::cloudflare::*;
+pub use self::dnslink::DnsLink;
 
 pub trait DnsRecordPutter {
     fn try_put_txt_record(&self, ipfs_cid: String) -> crate::error::BoxFuture<bool>;
@@ -51,10 +57,46 @@ impl DnsTxtRecordResponse {
             .map_err(|err| err.into())
     }
 
-    pub fn as_hash(&self) -> Option<&str> {
-        self.txt_data
-            .first()
-            .and_then(|x| x.get("dnslink=/ipfs/".len()..))
+    /// Like `lookup_txt_record`, but retries failures that may go away on their own, up to
+    /// `attempts` times starting `base_delay` apart.
+    pub fn lookup_txt_record_with_retry(
+        name: &str,
+        attempts: u32,
+        base_delay: Duration,
+    ) -> impl Future<Item = Option<Self>, Error = Error> {
+        let name = String::from(name);
+        retry("DNS lookup", attempts, base_delay, is_transient, move || {
+            Self::lookup_txt_record(&name)
+        })
+    }
+
+    /// The first of the TXT strings that is a DNSLink, wherever the provider put it.
+    pub fn dnslink(&self) -> Option<DnsLink> {
+        self.txt_data.iter().find_map(|txt| DnsLink::parse(txt))
+    }
+
+    /// The CID of the content the record points at, if it is an `/ipfs/` link.
+    pub fn as_hash(&self) -> Option<String> {
+        self.dnslink().and_then(DnsLink::into_cid)
+    }
+
+    /// Whether `new` points at other content than `last`. Unlike `==`, which compares the raw
+    /// TXT data, this ignores a provider reordering or reformatting the strings.
+    pub fn content_changed(last: &Option<Self>, new: &Option<Self>) -> bool {
+        last.as_ref().and_then(Self::dnslink) != new.as_ref().and_then(Self::dnslink)
+    }
+}
+
+/// Timeouts, SERVFAIL and connection errors may clear up within a poll. NXDOMAIN won't, so it
+/// isn't retried.
+fn is_transient(err: &Error) -> bool {
+    match **err {
+        ErrorKind::TrustDnsResolveError(ref err) => match err.kind() {
+            ResolveErrorKind::NoRecordsFound { .. } => false,
+            _ => true,
+        },
+        ErrorKind::IoError(_) => true,
+        _ => false,
     }
 }
 
@@ -68,3 +110,87 @@ impl PartialEq for DnsTxtRecordResponse {
         self.txt_data == other.txt_data
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use trust_dns_proto::op::Query;
+    use trust_dns_resolver::error::ResolveError;
+
+    fn resolve_error(kind: ResolveErrorKind) -> Error {
+        ResolveError::from(kind).into()
+    }
+
+    /// Fails the first lookup with `err`, then finds a record.
+    fn flaky_lookup(
+        err: ResolveErrorKind,
+        lookups: Rc<Cell<u32>>,
+    ) -> impl FnMut() -> futures::future::FutureResult<Option<DnsTxtRecordResponse>, Error> {
+        let mut err = Some(err);
+        move || {
+            lookups.set(lookups.get() + 1);
+            match err.take() {
+                Some(err) => futures::future::err(resolve_error(err)),
+                None => futures::future::ok(Some(DnsTxtRecordResponse {
+                    txt_data: vec![String::from("dnslink=/ipfs/QmTest")],
+                })),
+            }
+        }
+    }
+
+    #[test]
+    fn only_a_new_dnslink_changes_the_content() {
+        let record = |txt_data: &[&str]| {
+            Some(DnsTxtRecordResponse {
+                txt_data: txt_data.iter().map(|txt| String::from(*txt)).collect(),
+            })
+        };
+        let last = record(&["dnslink=/ipfs/QmOld", "v=1"]);
+        let reordered = record(&["v=1", "dnslink=/ipfs/QmOld"]);
+        assert!(last != reordered);
+        assert!(!DnsTxtRecordResponse::content_changed(&last, &reordered));
+        assert!(DnsTxtRecordResponse::content_changed(
+            &last,
+            &record(&["dnslink=/ipfs/QmNew", "v=1"])
+        ));
+        assert!(DnsTxtRecordResponse::content_changed(&None, &last));
+    }
+
+    #[test]
+    fn transient_failure_is_retried_within_the_poll() {
+        let lookups = Rc::new(Cell::new(0));
+        let record = actix::System::new("lrad-test")
+            .block_on(retry(
+                "DNS lookup",
+                3,
+                Duration::from_millis(1),
+                is_transient,
+                flaky_lookup(ResolveErrorKind::Timeout, lookups.clone()),
+            ))
+            .unwrap();
+        assert_eq!(record.unwrap().as_hash(), Some(String::from("QmTest")));
+        assert_eq!(lookups.get(), 2);
+    }
+
+    #[test]
+    fn nxdomain_is_not_retried() {
+        let lookups = Rc::new(Cell::new(0));
+        let res = actix::System::new("lrad-test").block_on(retry(
+            "DNS lookup",
+            3,
+            Duration::from_millis(1),
+            is_transient,
+            flaky_lookup(
+                ResolveErrorKind::NoRecordsFound {
+                    query: Query::new(),
+                    valid_until: None,
+                },
+                lookups.clone(),
+            ),
+        ));
+        assert!(res.is_err());
+        assert_eq!(lookups.get(), 1);
+    }
+}
@@ -1,64 +1,440 @@
-use actix_web::{client, HttpMessage};
+use actix_web::{client, error, HttpMessage};
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::future;
 use futures::prelude::*;
-use git2::Repository;
+use futures::sink::Wait;
+use futures::sync::mpsc;
 use percent_encoding::{utf8_percent_encode, QUERY_ENCODE_SET};
 use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, BufWriter, Write};
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+use std::thread;
 use tar::Builder;
+use tokio_tcp::TcpStream;
 use tokio_uds::UnixStream;
 
-use crate::error::Error;
-use crate::vcs::VcsError;
+use crate::error::{BoxFuture, Error, ErrorKind};
+use crate::retry::retry;
+use crate::runtime::ContainerRuntime;
 
 use std::time::Duration;
 
-pub fn build_image(
-    repo: &Repository,
+/// Where to reach the Docker daemon, written like DOCKER_HOST: `unix:///var/run/docker.sock` or
+/// `tcp://host:port`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum DockerConnection {
+    Unix(PathBuf),
+    Tcp(String),
+}
+
+impl Default for DockerConnection {
+    fn default() -> Self {
+        DockerConnection::Unix(PathBuf::from("/var/run/docker.sock"))
+    }
+}
+
+impl TryFrom<String> for DockerConnection {
+    type Error = String;
+
+    fn try_from(host: String) -> Result<Self, Self::Error> {
+        if let Some(path) = host.get("unix://".len()..).filter(|_| host.starts_with("unix://")) {
+            Ok(DockerConnection::Unix(PathBuf::from(path)))
+        } else if let Some(addr) = host.get("tcp://".len()..).filter(|_| host.starts_with("tcp://")) {
+            Ok(DockerConnection::Tcp(String::from(addr)))
+        } else {
+            Err(format!(
+                "Docker host {} must start with unix:// or tcp://",
+                host
+            ))
+        }
+    }
+}
+
+impl fmt::Display for DockerConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DockerConnection::Unix(path) => write!(f, "unix://{}", path.display()),
+            DockerConnection::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+impl From<DockerConnection> for String {
+    fn from(docker: DockerConnection) -> Self {
+        docker.to_string()
+    }
+}
+
+fn connect(docker: &DockerConnection) -> Box<Future<Item = client::Connection, Error = Error>> {
+    match docker {
+        DockerConnection::Unix(path) => Box::new(
+            UnixStream::connect(path)
+                .map(client::Connection::from_stream)
+                .map_err(|err| Error::from(err)),
+        ),
+        DockerConnection::Tcp(addr) => {
+            let socket_addr = match addr.to_socket_addrs().map(|mut addrs| addrs.next()) {
+                Ok(Some(socket_addr)) => socket_addr,
+                Ok(None) => {
+                    return Box::new(future::err(
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!("{} did not resolve to any address", addr),
+                        )
+                        .into(),
+                    ))
+                }
+                Err(err) => return Box::new(future::err(err.into())),
+            };
+            Box::new(
+                TcpStream::connect(&socket_addr)
+                    .map(client::Connection::from_stream)
+                    .map_err(|err| Error::from(err)),
+            )
+        }
+    }
+}
+
+/// How Docker requests that fail transiently, e.g. while a loaded daemon is still coming up, are
+/// retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    pub attempts: u32,
+    pub base_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            base_delay: Duration::from_millis(500),
+        }
+    }
+}
+
+/// The Docker daemon to talk to along with how requests to it are made.
+#[derive(Clone, Debug, Default)]
+pub struct Docker {
+    pub connection: DockerConnection,
+    pub retry: RetryPolicy,
+    /// Whether build contexts are gzipped before they are sent
+    pub compress_context: bool,
+}
+
+/// Connection failures and 5xx responses are worth another try; client errors are not.
+fn is_transient(err: &Error) -> bool {
+    match **err {
+        ErrorKind::IoError(_) | ErrorKind::SendRequestError(_) => true,
+        ErrorKind::DockerApiError { status, .. } => status >= 500,
+        _ => false,
+    }
+}
+
+/// Connects and sends a request built by `f`, retrying both according to the policy of `docker`.
+fn with_retry<F, Fut>(docker: &Docker, f: F) -> impl Future<Item = Fut::Item, Error = Error>
+where
+    F: FnMut(client::Connection) -> Fut + Clone + 'static,
+    Fut: IntoFuture<Error = Error> + 'static,
+{
+    let connection = docker.connection.clone();
+    retry(
+        "Docker request",
+        docker.retry.attempts,
+        docker.retry.base_delay,
+        is_transient,
+        move || {
+            let mut f = f.clone();
+            connect(&connection).and_then(move |connection| f(connection))
+        },
+    )
+}
+
+/// Like `with_retry`, but only retries connecting, for requests that aren't safe to send twice,
+/// e.g. creating a container. Once a request is sent, a failure doesn't tell whether Docker acted
+/// on it already.
+fn with_connect_retry<F, Fut>(docker: &Docker, f: F) -> impl Future<Item = Fut::Item, Error = Error>
+where
+    F: FnOnce(client::Connection) -> Fut + 'static,
+    Fut: IntoFuture<Error = Error> + 'static,
+{
+    let connection = docker.connection.clone();
+    retry(
+        "Docker connection",
+        docker.retry.attempts,
+        docker.retry.base_delay,
+        is_transient,
+        move || connect(&connection),
+    )
+    .and_then(f)
+}
+
+/// Fails with the status and whatever Docker said in the body of the response.
+fn api_error(
+    res: client::ClientResponse,
+) -> impl Future<Item = client::ClientResponse, Error = Error> {
+    let status = res.status().as_u16();
+    res.body().then(move |body| -> Result<client::ClientResponse, Error> {
+        let body = body
+            .map(|body| String::from_utf8_lossy(&body).trim().to_owned())
+            .unwrap_or_default();
+        Err(ErrorKind::DockerApiError { status, body }.into())
+    })
+}
+
+/// Fails on 5xx responses so that they are retried rather than mistaken for a result.
+fn reject_server_error(
+    res: client::ClientResponse,
+) -> impl Future<Item = client::ClientResponse, Error = Error> {
+    if res.status().is_server_error() {
+        future::Either::A(api_error(res))
+    } else {
+        future::Either::B(future::ok(res))
+    }
+}
+
+/// Fails on any response that isn't a success, rather than reading it as a result.
+fn reject_unsuccessful(
+    res: client::ClientResponse,
+) -> impl Future<Item = client::ClientResponse, Error = Error> {
+    if res.status().is_success() {
+        future::Either::B(future::ok(res))
+    } else {
+        future::Either::A(api_error(res))
+    }
+}
+
+/// Forwards everything written to it as chunks of a request body stream.
+struct ChannelWriter(Wait<mpsc::Sender<std::result::Result<Bytes, String>>>);
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(Ok(Bytes::from(buf)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "build request was closed"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0
+            .flush()
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "build request was closed"))
+    }
+}
+
+/// Number of chunks of the build context that may be buffered ahead of the request.
+const BUILD_CONTEXT_CHANNEL_SIZE: usize = 16;
+const BUILD_CONTEXT_CHUNK_SIZE: usize = 64 * 1024;
+
+fn tar_dir<W: Write>(path: &Path, writer: W) -> io::Result<W> {
+    let mut ar = Builder::new(writer);
+    ar.append_dir_all(".", path)?;
+    ar.into_inner()
+}
+
+/// Tars `path` on a separate thread, streaming the archive as it is written so that it is never
+/// fully held in memory. Docker detects a gzipped context on its own, so `compress` needs no
+/// matching header.
+fn stream_build_context(
+    path: PathBuf,
+    compress: bool,
+) -> impl Stream<Item = Bytes, Error = actix_web::Error> {
+    let (tx, rx) = mpsc::channel(BUILD_CONTEXT_CHANNEL_SIZE);
+    thread::spawn(move || {
+        debug!("Streaming tarball");
+        let writer = BufWriter::with_capacity(
+            BUILD_CONTEXT_CHUNK_SIZE,
+            ChannelWriter(tx.clone().wait()),
+        );
+        let res = if compress {
+            tar_dir(&path, GzEncoder::new(writer, Compression::default()))
+                .and_then(|encoder| encoder.finish())
+        } else {
+            tar_dir(&path, writer)
+        }
+        .and_then(|mut writer| writer.flush());
+        match res {
+            Ok(()) => debug!("Tarball streamed"),
+            Err(err) => {
+                error!("Failed to stream tarball {:?}", err);
+                let _ = tx.send(Err(err.to_string())).wait();
+            }
+        }
+    });
+    rx.then(|chunk| match chunk {
+        Ok(Ok(bytes)) => Ok(bytes),
+        Ok(Err(err)) => Err(error::ErrorInternalServerError(err)),
+        Err(()) => Err(error::ErrorInternalServerError(
+            "build context stream was closed",
+        )),
+    })
+}
+
+/// Builds an image using `context_path` as the build context, gzipping it on the way if the
+/// connection is set up to.
+pub fn build_image_from_dir(
+    docker: &Docker,
+    context_path: PathBuf,
     image_name: String,
 ) -> impl Future<Item = bool, Error = Error> {
-    let repo_path = repo.path().parent().unwrap().to_path_buf();
-    let is_bare = repo.is_bare();
-    debug!("Opening Unix socket");
-    UnixStream::connect("/var/run/docker.sock")
+    let compress_context = docker.compress_context;
+    debug!("Connecting to Docker");
+    with_connect_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send build request");
+        client::post(format!(
+            "/v1.39/build?t={}",
+            utf8_percent_encode(&image_name, QUERY_ENCODE_SET)
+        ))
+        .header("Host", "lrad")
+        .header("Content-Type", "application/x-tar")
+        .with_connection(connection)
+        .timeout(Duration::from_secs(3600))
+        .streaming(stream_build_context(context_path, compress_context))
+        .map(|x| {
+            debug!("Sending Docker build request...");
+            x
+        })
+        .unwrap()
+        .send()
         .map_err(|err| Error::from(err))
-        .and_then(move |stream| {
-            if is_bare {
-                return Err(VcsError::RepoShouldNotBeBare.into());
+        .and_then(|res| {
+            let is_success = res.status().is_success();
+            debug!("Parsing Docker build response...");
+            res.payload()
+                .map_err(|err| Error::from(err))
+                .fold(BuildOutputParser::default(), |mut parser, chunk| {
+                    parser.feed(&chunk);
+                    Ok::<_, Error>(parser)
+                })
+                .and_then(move |parser| parser.finish(is_success))
+        })
+    })
+}
+
+#[derive(Deserialize)]
+struct BuildOutput {
+    stream: Option<String>,
+    error: Option<String>,
+    #[serde(rename = "errorDetail")]
+    error_detail: Option<BuildErrorDetail>,
+    /// Set instead of the above when the request itself is rejected
+    message: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct BuildErrorDetail {
+    message: Option<String>,
+}
+
+/// Incrementally parses the newline-delimited JSON that the build and pull endpoints stream back.
+#[derive(Default)]
+struct BuildOutputParser {
+    pending: Vec<u8>,
+    error: Option<String>,
+}
+
+impl BuildOutputParser {
+    fn feed(&mut self, chunk: &[u8]) {
+        self.pending.extend_from_slice(chunk);
+        while let Some(newline) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=newline).collect();
+            self.parse_line(&line);
+        }
+    }
+
+    fn parse_line(&mut self, line: &[u8]) {
+        if line.iter().all(|byte| byte.is_ascii_whitespace()) {
+            return;
+        }
+        match serde_json::from_slice::<BuildOutput>(line) {
+            Ok(output) => {
+                if let Some(stream) = output.stream {
+                    debug!("Docker build: {}", stream.trim_end());
+                }
+                let error = output
+                    .error_detail
+                    .and_then(|detail| detail.message)
+                    .or(output.error)
+                    .or(output.message);
+                if error.is_some() && self.error.is_none() {
+                    self.error = error;
+                }
             }
-            debug!("Unix stream opened, preparing to send build request");
-            debug!("Building tarball");
-            // TODO: convert this to actor and stream contents to request
-            let mut ar = Builder::new(Vec::new());
-            ar.append_dir_all(".", &repo_path).unwrap();
-            ar.finish().unwrap();
-            debug!("Tarball ready");
-            Ok((stream, ar))
+            Err(err) => debug!("Unparseable Docker build output {:?}", err),
+        }
+    }
+
+    /// The first error Docker reported, if any.
+    fn into_error(mut self, is_success: bool) -> Option<String> {
+        let pending = std::mem::replace(&mut self.pending, Vec::new());
+        self.parse_line(&pending);
+        match self.error {
+            Some(error) => Some(error),
+            None if !is_success => Some(String::from("Docker rejected the request")),
+            None => None,
+        }
+    }
+
+    fn finish(self, is_success: bool) -> Result<bool, Error> {
+        match self.into_error(is_success) {
+            Some(error) => Err(ErrorKind::DockerBuildError(error).into()),
+            None => Ok(true),
+        }
+    }
+}
+
+/// Pulls `image_ref`, e.g. "registry.example.com/app:1.2". Without a tag, only `latest` is pulled
+/// rather than every tag of the image.
+pub fn pull_image(docker: &Docker, image_ref: String) -> impl Future<Item = bool, Error = Error> {
+    let has_tag = image_ref
+        .rsplit('/')
+        .next()
+        .map(|name| name.contains(':') || name.contains('@'))
+        .unwrap_or(false);
+    let image_ref = if has_tag {
+        image_ref
+    } else {
+        format!("{}:latest", image_ref)
+    };
+    debug!("Connecting to Docker");
+    with_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send pull request");
+        client::post(format!(
+            "/v1.39/images/create?fromImage={}",
+            utf8_percent_encode(&image_ref, QUERY_ENCODE_SET)
+        ))
+        .header("Host", "lrad")
+        .with_connection(connection)
+        .timeout(Duration::from_secs(3600))
+        .finish()
+        .map(|x| {
+            debug!("Sending Docker pull request...");
+            x
         })
-        .and_then(move |(stream, ar)| {
-            client::post(format!(
-                "/v1.39/build?t={}",
-                utf8_percent_encode(&image_name, QUERY_ENCODE_SET)
-            ))
-            .header("Host", "lrad")
-            .with_connection(client::Connection::from_stream(stream))
-            .timeout(Duration::from_secs(3600))
-            .body(ar.into_inner().unwrap())
-            .map(|x| {
-                debug!("Sending Docker build request...");
-                x
-            })
-            .unwrap()
-            .send()
-            .map_err(|err| Error::from(err))
-            .and_then(|res| {
-                let is_success = res.status().is_success();
-                res.body()
-                    .and_then(|bytes| {
-                        debug!("Parsing Docker build response... {:?}", bytes);
-                        Ok(())
-                    })
-                    .then(move |_| Ok(is_success))
-            })
+        .unwrap()
+        .send()
+        .map_err(|err| Error::from(err))
+        .and_then(reject_server_error)
+        .and_then(|res| {
+            let is_success = res.status().is_success();
+            debug!("Parsing Docker pull response...");
+            res.payload()
+                .map_err(|err| Error::from(err))
+                .fold(BuildOutputParser::default(), |mut parser, chunk| {
+                    parser.feed(&chunk);
+                    Ok::<_, Error>(parser)
+                })
+                .and_then(move |parser| match parser.into_error(is_success) {
+                    Some(error) => Err(ErrorKind::DockerPullError(error).into()),
+                    None => Ok(true),
+                })
         })
+    })
 }
 
 #[derive(Deserialize)]
@@ -69,28 +445,66 @@ pub struct CreateContainerResponse {
     pub warnings: Option<Vec<String>>,
 }
 
-#[derive(Serialize)]
-struct CreateContainerRequest {
+/// Label marking which lrad deployment a container belongs to.
+pub const DEPLOYMENT_LABEL: &str = "io.lrad.deployment";
+
+#[derive(Serialize, Clone)]
+pub struct CreateContainerRequest {
     #[serde(rename = "Image")]
-    image: String,
+    pub image: String,
+    #[serde(rename = "Labels")]
+    pub labels: HashMap<String, String>,
+    #[serde(rename = "Env")]
+    pub env: Vec<String>,
     #[serde(rename = "HostConfig")]
-    host_config: Option<HostConfig>,
+    pub host_config: Option<HostConfig>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Clone)]
 pub struct HostConfig {
     #[serde(rename = "PublishAllPorts")]
     pub publish_all_ports: Option<bool>,
     #[serde(rename = "PortBindings")]
-    pub port_bindings: HashMap<String, Vec<PortBinding>>,
+    pub port_bindings: Option<HashMap<String, Vec<PortBinding>>>,
+    #[serde(rename = "Binds")]
+    pub binds: Vec<String>,
+    #[serde(rename = "RestartPolicy")]
+    pub restart_policy: RestartPolicy,
+    #[serde(rename = "Memory", skip_serializing_if = "Option::is_none")]
+    pub memory: Option<i64>,
+    #[serde(rename = "NanoCpus", skip_serializing_if = "Option::is_none")]
+    pub nano_cpus: Option<i64>,
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Clone, Debug)]
+pub struct RestartPolicy {
+    #[serde(rename = "Name")]
+    pub name: &'static str,
+    #[serde(rename = "MaximumRetryCount")]
+    pub maximum_retry_count: u32,
+}
+
+impl From<crate::config::RestartPolicy> for RestartPolicy {
+    fn from(other: crate::config::RestartPolicy) -> Self {
+        use crate::config::RestartPolicy::*;
+        Self {
+            name: match other {
+                No => "no",
+                OnFailure => "on-failure",
+                Always => "always",
+                UnlessStopped => "unless-stopped",
+            },
+            maximum_retry_count: 0,
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
 pub struct PortBinding {
     #[serde(rename = "HostIp")]
-    host_ip: Option<String>,
+    pub host_ip: Option<String>,
     #[serde(rename = "HostPort")]
-    host_port: String,
+    pub host_port: String,
 }
 
 impl From<&crate::config::PortBinding> for PortBinding {
@@ -103,63 +517,69 @@ impl From<&crate::config::PortBinding> for PortBinding {
 }
 
 pub fn create_new_container(
-    image: String,
+    docker: &Docker,
     container_name: Option<String>,
-    host_config: Option<HostConfig>,
+    request: CreateContainerRequest,
 ) -> impl Future<Item = CreateContainerResponse, Error = Error> {
-    UnixStream::connect("/var/run/docker.sock")
-        .map_err(|err| Error::from(err))
-        .and_then(move |stream| {
-            client::post("/v1.39/containers/create")
-                .header("Host", "lrad")
-                .with_connection(client::Connection::from_stream(stream))
-                .timeout(Duration::from_secs(30))
-                .json(CreateContainerRequest {
-                    image,
-                    host_config,
-                })
-                .map(|x| {
-                    debug!("Sending Docker create container...");
-                    x
-                })
-                .unwrap()
-                .send()
-                .map_err(|err| Error::from(err))
-                .and_then(|res| res.json().map_err(|err| Error::from(err)))
-        })
+    with_connect_retry(docker, move |connection| {
+        let path = match &container_name {
+            Some(container_name) => format!(
+                "/v1.39/containers/create?name={}",
+                utf8_percent_encode(container_name, QUERY_ENCODE_SET)
+            ),
+            None => String::from("/v1.39/containers/create"),
+        };
+        client::post(path)
+            .header("Host", "lrad")
+            .with_connection(connection)
+            .timeout(Duration::from_secs(30))
+            .json(request)
+            .map(|x| {
+                debug!("Sending Docker create container...");
+                x
+            })
+            .unwrap()
+            .send()
+            .map_err(|err| Error::from(err))
+            .and_then(reject_unsuccessful)
+            .and_then(|res| res.json().map_err(|err| Error::from(err)))
+    })
 }
 
+/// Removes the container, returning false if there was no such container to begin with.
 pub fn force_remove_running_container(
+    docker: &Docker,
     container_id: String,
 ) -> impl Future<Item = bool, Error = Error> {
-    debug!("Opening Unix socket");
+    debug!("Connecting to Docker");
     debug!("Preparing to remove container {}", container_id);
-    UnixStream::connect("/var/run/docker.sock")
-        .map_err(|err| Error::from(err))
-        .and_then(move |stream| {
-            debug!("Unix stream opened, preparing to send build request");
-            client::delete(format!("/v1.39/containers/{}?force=true", container_id))
-                .header("Host", "lrad")
-                .with_connection(client::Connection::from_stream(stream))
-                .timeout(Duration::from_secs(30))
-                .finish()
-                .map(|x| {
-                    debug!("Sending Docker remove containers request...");
-                    x
-                })
-                .unwrap()
-                .send()
-                .map_err(|err| Error::from(err))
-                .and_then(|res| {
-                    let is_success = res.status().is_success();
-                    res.body()
-                        .and_then(|bytes| {
-                            debug!("Parsing Docker remove container response... {:?}", bytes);
-                            Ok(())
-                        })
-                        .then(move |_| Ok(is_success))
-                })
-        })
+    with_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send build request");
+        client::delete(format!("/v1.39/containers/{}?force=true", container_id))
+            .header("Host", "lrad")
+            .with_connection(connection)
+            .timeout(Duration::from_secs(30))
+            .finish()
+            .map(|x| {
+                debug!("Sending Docker remove containers request...");
+                x
+            })
+            .unwrap()
+            .send()
+            .map_err(|err| Error::from(err))
+            .and_then(|res| {
+                if res.status().as_u16() == 404 {
+                    debug!("There is no such container to remove");
+                    return future::Either::A(future::ok(false));
+                }
+                future::Either::B(reject_unsuccessful(res).and_then(|res| {
+                    res.body().then(|bytes| {
+                        debug!("Parsing Docker remove container response... {:?}", bytes);
+                        Ok(true)
+                    })
+                }))
+            })
+    })
 }
 
 #[derive(Deserialize)]
@@ -168,30 +588,35 @@ pub struct ListContainersResponse {
     pub id: String,
     #[serde(rename = "Image")]
     pub image: String,
+    #[serde(rename = "ImageID")]
+    pub image_id: String,
+    #[serde(rename = "Labels")]
+    pub labels: Option<HashMap<String, String>>,
     #[serde(rename = "State")]
     pub state: String,
 }
 
-pub fn list_containers() -> impl Future<Item = Vec<ListContainersResponse>, Error = Error> {
-    debug!("Opening Unix socket");
-    UnixStream::connect("/var/run/docker.sock")
-        .map_err(|err| Error::from(err))
-        .and_then(move |stream| {
-            debug!("Unix stream opened, preparing to send list request");
-            client::get("/v1.39/containers/json")
-                .header("Host", "lrad")
-                .with_connection(client::Connection::from_stream(stream))
-                .timeout(Duration::from_secs(30))
-                .finish()
-                .map(|x| {
-                    debug!("Sending Docker list containers request...");
-                    x
-                })
-                .unwrap()
-                .send()
-                .map_err(|err| Error::from(err))
-                .and_then(|res| res.json().map_err(|err| Error::from(err)))
-        })
+pub fn list_containers(
+    docker: &Docker,
+) -> impl Future<Item = Vec<ListContainersResponse>, Error = Error> {
+    debug!("Connecting to Docker");
+    with_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send list request");
+        client::get("/v1.39/containers/json?all=true")
+            .header("Host", "lrad")
+            .with_connection(connection)
+            .timeout(Duration::from_secs(30))
+            .finish()
+            .map(|x| {
+                debug!("Sending Docker list containers request...");
+                x
+            })
+            .unwrap()
+            .send()
+            .map_err(|err| Error::from(err))
+            .and_then(reject_unsuccessful)
+            .and_then(|res| res.json().map_err(|err| Error::from(err)))
+    })
 }
 
 #[derive(Deserialize)]
@@ -202,56 +627,365 @@ pub struct ListImagesResponse {
     pub repo_tags: Vec<String>,
     #[serde(rename = "Containers")]
     pub containers: i32,
+    #[serde(rename = "Created")]
+    pub created: i64,
 }
 
-pub fn list_images() -> impl Future<Item = Vec<ListImagesResponse>, Error = Error> {
-    debug!("Opening Unix socket");
-    UnixStream::connect("/var/run/docker.sock")
+pub fn list_images(
+    docker: &Docker,
+) -> impl Future<Item = Vec<ListImagesResponse>, Error = Error> {
+    debug!("Connecting to Docker");
+    with_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send list request");
+        client::get("/v1.39/images/json")
+            .header("Host", "lrad")
+            .with_connection(connection)
+            .timeout(Duration::from_secs(30))
+            .finish()
+            .map(|x| {
+                debug!("Sending Docker list containers request...");
+                x
+            })
+            .unwrap()
+            .send()
+            .map_err(|err| Error::from(err))
+            .and_then(reject_unsuccessful)
+            .and_then(|res| res.json().map_err(|err| Error::from(err)))
+    })
+}
+
+/// Adds `repo:tag` as another name for `image`.
+pub fn tag_image(
+    docker: &Docker,
+    image: String,
+    repo: String,
+    tag: String,
+) -> impl Future<Item = bool, Error = Error> {
+    debug!("Connecting to Docker");
+    with_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send tag request");
+        client::post(format!(
+            "/v1.39/images/{}/tag?repo={}&tag={}",
+            image,
+            utf8_percent_encode(&repo, QUERY_ENCODE_SET),
+            utf8_percent_encode(&tag, QUERY_ENCODE_SET)
+        ))
+        .header("Host", "lrad")
+        .with_connection(connection)
+        .timeout(Duration::from_secs(30))
+        .finish()
+        .map(|x| {
+            debug!("Sending Docker tag image request...");
+            x
+        })
+        .unwrap()
+        .send()
         .map_err(|err| Error::from(err))
-        .and_then(move |stream| {
-            debug!("Unix stream opened, preparing to send list request");
-            client::get("/v1.39/images/json")
-                .header("Host", "lrad")
-                .with_connection(client::Connection::from_stream(stream))
-                .timeout(Duration::from_secs(30))
-                .finish()
-                .map(|x| {
-                    debug!("Sending Docker list containers request...");
-                    x
+        .and_then(reject_server_error)
+        .and_then(|res| {
+            let is_success = res.status().is_success();
+            res.body()
+                .and_then(|bytes| {
+                    debug!("Parsing Docker tag image response... {:?}", bytes);
+                    Ok(())
                 })
-                .unwrap()
-                .send()
-                .map_err(|err| Error::from(err))
-                .and_then(|res| res.json().map_err(|err| Error::from(err)))
+                .then(move |_| Ok(is_success))
         })
+    })
 }
 
-pub fn start_container(container_id: String) -> impl Future<Item = bool, Error = Error> {
-    debug!("Opening Unix socket");
-    UnixStream::connect("/var/run/docker.sock")
-        .map_err(|err| Error::from(err))
-        .and_then(move |stream| {
-            debug!("Unix stream opened, preparing to send start request");
-            client::post(format!("/v1.39/containers/{}/start", container_id))
-                .header("Host", "lrad")
-                .with_connection(client::Connection::from_stream(stream))
-                .timeout(Duration::from_secs(30))
-                .finish()
-                .map(|x| {
-                    debug!("Sending Docker start request...");
-                    x
+/// Removes the image name `image`, and the image itself if nothing else refers to it. Images
+/// still used by a container are left alone.
+pub fn remove_image(docker: &Docker, image: String) -> impl Future<Item = bool, Error = Error> {
+    debug!("Connecting to Docker");
+    with_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send remove image request");
+        client::delete(format!("/v1.39/images/{}", image))
+            .header("Host", "lrad")
+            .with_connection(connection)
+            .timeout(Duration::from_secs(30))
+            .finish()
+            .map(|x| {
+                debug!("Sending Docker remove image request...");
+                x
+            })
+            .unwrap()
+            .send()
+            .map_err(|err| Error::from(err))
+            .and_then(reject_server_error)
+            .and_then(|res| {
+                let is_success = res.status().is_success();
+                res.body()
+                    .and_then(|bytes| {
+                        debug!("Parsing Docker remove image response... {:?}", bytes);
+                        Ok(())
+                    })
+                    .then(move |_| Ok(is_success))
+            })
+    })
+}
+
+pub fn start_container(
+    docker: &Docker,
+    container_id: String,
+) -> impl Future<Item = bool, Error = Error> {
+    debug!("Connecting to Docker");
+    with_connect_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send start request");
+        client::post(format!("/v1.39/containers/{}/start", container_id))
+            .header("Host", "lrad")
+            .with_connection(connection)
+            .timeout(Duration::from_secs(30))
+            .finish()
+            .map(|x| {
+                debug!("Sending Docker start request...");
+                x
+            })
+            .unwrap()
+            .send()
+            .map_err(|err| Error::from(err))
+            .and_then(reject_unsuccessful)
+            .and_then(|res| {
+                res.body().then(|bytes| {
+                    debug!("Parsing Docker start container response... {:?}", bytes);
+                    Ok(true)
                 })
-                .unwrap()
-                .send()
-                .map_err(|err| Error::from(err))
-                .and_then(|res| {
-                    let is_success = res.status().is_success();
-                    res.body()
-                        .and_then(|bytes| {
-                            debug!("Parsing Docker start container response... {:?}", bytes);
-                            Ok(())
-                        })
-                        .then(move |_| Ok(is_success))
+            })
+    })
+}
+
+#[derive(Deserialize)]
+pub struct ContainerState {
+    #[serde(rename = "Running")]
+    pub running: bool,
+    #[serde(rename = "Health")]
+    pub health: Option<ContainerHealth>,
+}
+
+#[derive(Deserialize)]
+pub struct ContainerHealth {
+    #[serde(rename = "Status")]
+    pub status: String,
+}
+
+#[derive(Deserialize)]
+pub struct InspectContainerResponse {
+    #[serde(rename = "Id")]
+    pub id: String,
+    #[serde(rename = "State")]
+    pub state: ContainerState,
+}
+
+pub fn inspect_container(
+    docker: &Docker,
+    container_id: String,
+) -> impl Future<Item = InspectContainerResponse, Error = Error> {
+    debug!("Connecting to Docker");
+    with_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send inspect request");
+        client::get(format!("/v1.39/containers/{}/json", container_id))
+            .header("Host", "lrad")
+            .with_connection(connection)
+            .timeout(Duration::from_secs(30))
+            .finish()
+            .map(|x| {
+                debug!("Sending Docker inspect container request...");
+                x
+            })
+            .unwrap()
+            .send()
+            .map_err(|err| Error::from(err))
+            .and_then(reject_server_error)
+            .and_then(|res| res.json().map_err(|err| Error::from(err)))
+    })
+}
+
+pub fn rename_container(
+    docker: &Docker,
+    container_id: String,
+    container_name: String,
+) -> impl Future<Item = bool, Error = Error> {
+    debug!("Connecting to Docker");
+    with_connect_retry(docker, move |connection| {
+        debug!("Docker connection opened, preparing to send rename request");
+        client::post(format!(
+            "/v1.39/containers/{}/rename?name={}",
+            container_id,
+            utf8_percent_encode(&container_name, QUERY_ENCODE_SET)
+        ))
+        .header("Host", "lrad")
+        .with_connection(connection)
+        .timeout(Duration::from_secs(30))
+        .finish()
+        .map(|x| {
+            debug!("Sending Docker rename container request...");
+            x
+        })
+        .unwrap()
+        .send()
+        .map_err(|err| Error::from(err))
+        .and_then(reject_server_error)
+        .and_then(|res| {
+            let is_success = res.status().is_success();
+            res.body()
+                .and_then(|bytes| {
+                    debug!("Parsing Docker rename container response... {:?}", bytes);
+                    Ok(())
                 })
+                .then(move |_| Ok(is_success))
         })
+    })
+}
+
+impl ContainerRuntime for Docker {
+    fn build(&self, context: PathBuf, image_name: String) -> BoxFuture<bool> {
+        Box::new(build_image_from_dir(self, context, image_name))
+    }
+
+    fn pull(&self, image_ref: String) -> BoxFuture<bool> {
+        Box::new(pull_image(self, image_ref))
+    }
+
+    fn create(&self, name: Option<String>, request: CreateContainerRequest) -> BoxFuture<String> {
+        Box::new(create_new_container(self, name, request).map(|response| response.id))
+    }
+
+    fn start(&self, container_id: String) -> BoxFuture<bool> {
+        Box::new(start_container(self, container_id))
+    }
+
+    fn inspect(&self, container_id: String) -> BoxFuture<InspectContainerResponse> {
+        Box::new(inspect_container(self, container_id))
+    }
+
+    fn rename(&self, container_id: String, container_name: String) -> BoxFuture<bool> {
+        Box::new(rename_container(self, container_id, container_name))
+    }
+
+    fn remove(&self, container_id: String) -> BoxFuture<bool> {
+        Box::new(force_remove_running_container(self, container_id))
+    }
+
+    fn list(&self) -> BoxFuture<Vec<ListContainersResponse>> {
+        Box::new(list_containers(self))
+    }
+
+    fn list_images(&self) -> BoxFuture<Vec<ListImagesResponse>> {
+        Box::new(list_images(self))
+    }
+
+    fn tag(&self, image: String, repo: String, tag: String) -> BoxFuture<bool> {
+        Box::new(tag_image(self, image, repo, tag))
+    }
+
+    fn remove_image(&self, image: String) -> BoxFuture<bool> {
+        Box::new(remove_image(self, image))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::serve_once;
+
+    #[test]
+    fn build_output_parser_succeeds_without_errors() {
+        let mut parser = BuildOutputParser::default();
+        parser.feed(b"{\"stream\":\"Step 1/2 : FROM alpine\\n\"}\n{\"str");
+        parser.feed(b"eam\":\"Successfully built 1234\\n\"}\n");
+        assert!(parser.finish(true).unwrap());
+    }
+
+    #[test]
+    fn build_output_parser_reports_build_errors() {
+        let mut parser = BuildOutputParser::default();
+        parser.feed(b"{\"stream\":\"Step 2/2 : RUN false\\n\"}\n");
+        parser.feed(b"{\"errorDetail\":{\"code\":1,\"message\":\"The command '/bin/sh -c false' returned a non-zero code: 1\"},\"error\":\"The command '/bin/sh -c false' returned a non-zero code: 1\"}\n");
+        match parser.finish(true) {
+            Err(box ErrorKind::DockerBuildError(message)) => assert_eq!(
+                message,
+                "The command '/bin/sh -c false' returned a non-zero code: 1"
+            ),
+            _ => panic!("expected a DockerBuildError"),
+        }
+    }
+
+    #[test]
+    fn compressed_build_context_is_a_gzipped_tar() {
+        let context = tempfile::tempdir().unwrap();
+        std::fs::write(context.path().join("Dockerfile"), "FROM alpine\n").unwrap();
+        let chunks = stream_build_context(context.path().to_path_buf(), true)
+            .collect()
+            .wait()
+            .unwrap();
+        let archive: Vec<u8> = chunks.iter().flat_map(|chunk| chunk.iter().cloned()).collect();
+        assert_eq!(&archive[..2], &[0x1f, 0x8b]);
+        let mut ar = tar::Archive::new(flate2::read::GzDecoder::new(archive.as_slice()));
+        let paths: Vec<PathBuf> = ar
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().into_owned())
+            .collect();
+        assert!(paths.iter().any(|path| path.ends_with("Dockerfile")));
+    }
+
+    #[test]
+    fn stopped_containers_are_listed_too() {
+        let (addr, request) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 2\r\n\r\n[]",
+        );
+        let docker = Docker {
+            connection: DockerConnection::Tcp(addr),
+            ..Docker::default()
+        };
+        let containers = actix::System::new("lrad-test")
+            .block_on(list_containers(&docker))
+            .unwrap();
+        assert!(containers.is_empty());
+        assert!(request
+            .join()
+            .unwrap()
+            .starts_with("GET /v1.39/containers/json?all=true HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn unsuccessful_responses_carry_what_docker_said() {
+        let (addr, _request) = serve_once(
+            "HTTP/1.1 409 Conflict\r\nContent-Type: application/json\r\nContent-Length: 37\r\n\r\n{\"message\":\"container abc is paused\"}",
+        );
+        let docker = Docker {
+            connection: DockerConnection::Tcp(addr),
+            ..Docker::default()
+        };
+        let res =
+            actix::System::new("lrad-test").block_on(start_container(&docker, String::from("abc")));
+        match res {
+            Err(box ErrorKind::DockerApiError { status, body }) => {
+                assert_eq!(status, 409);
+                assert_eq!(body, "{\"message\":\"container abc is paused\"}");
+            }
+            _ => panic!("expected a DockerApiError"),
+        }
+    }
+
+    #[test]
+    fn requests_that_reached_docker_are_not_sent_again() {
+        let (addr, _request) = serve_once(
+            "HTTP/1.1 503 Service Unavailable\r\nContent-Type: application/json\r\nContent-Length: 20\r\n\r\n{\"message\":\"busy\"}",
+        );
+        let docker = Docker {
+            connection: DockerConnection::Tcp(addr),
+            retry: RetryPolicy {
+                attempts: 3,
+                base_delay: Duration::from_millis(1),
+            },
+            ..Docker::default()
+        };
+        let res =
+            actix::System::new("lrad-test").block_on(start_container(&docker, String::from("abc")));
+        match res {
+            Err(box ErrorKind::DockerApiError { status, .. }) => assert_eq!(status, 503),
+            other => panic!("expected a DockerApiError, got {:?}", other),
+        }
+    }
 }
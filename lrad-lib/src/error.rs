@@ -1,3 +1,5 @@
+use super::config::ConfigError;
+use super::dns::CloudflareApiErrorMessage;
 use super::vcs::VcsError;
 use actix_web::client::SendRequestError;
 use actix_web::error::JsonPayloadError;
@@ -6,8 +8,12 @@ use actix_web::Error as ActixWebError;
 use curl::{Error as CurlError, FormError as CurlFormError};
 use git2::Error as Git2Error;
 use serde_json::Error as SerdeJsonError;
+use std::error::Error as StdError;
+use std::fmt;
 use std::io::Error as IoError;
+use std::path::PathBuf;
 use std::str::Utf8Error;
+use tokio_timer::Error as TimerError;
 use toml::de::Error as TomlDeError;
 use toml::ser::Error as TomlSerError;
 use trust_dns_resolver::error::ResolveError;
@@ -29,6 +35,173 @@ pub enum ErrorKind {
     PayloadError(PayloadError),
     SendRequestError(SendRequestError),
     TrustDnsResolveError(ResolveError),
+    IpfsDaemonUnreachable(String),
+    IpfsApiError(u32, String),
+    /// An add to IPFS that answered without any entry, not even the root directory
+    NothingAddedToIpfs(PathBuf),
+    /// Blocks of the CID that were missing locally and couldn't be fetched again
+    IpfsRefetchIncomplete(String),
+    TimerError(TimerError),
+    ContainerNotRunning(String),
+    ContainerUnhealthy(String),
+    /// A container that couldn't be given its name, e.g. after replacing the old one
+    ContainerRenameFailed(String, String),
+    SecretFileUnreadable(PathBuf, IoError),
+    DockerBuildError(String),
+    /// A response from the Docker daemon that wasn't a success, with what it said about it
+    DockerApiError { status: u16, body: String },
+    DockerPullError(String),
+    RuntimeCommandFailed(String),
+    /// A git command that couldn't run or exited unsuccessfully, with what it printed to stderr
+    GitCommandFailed(String, String),
+    RemotePinNotConfigured,
+    RemotePinRejected(u32, String),
+    TempDirCreationFailed(PathBuf, IoError),
+    CloudflareApiError(Vec<CloudflareApiErrorMessage>),
+    /// A DNS record TTL that is neither 1 for automatic nor within the range Cloudflare accepts
+    InvalidDnsTtl(u32),
+    InvalidConfig(Vec<ConfigError>),
+    /// A config name relative to the repo that would end up outside of it
+    ConfigPathOutsideRepo(PathBuf),
+    /// A target asked for by its dns_record_name that the daemon config doesn't have
+    UnknownDeployTarget(String),
+    /// No image of the deployment to roll back to, either tagged with the CID or at all
+    RollbackImageNotFound(String, Option<String>),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrorKind::TomlSer(err) => write!(f, "could not write TOML: {}", err),
+            ErrorKind::TomlDe(err) => write!(f, "could not parse TOML: {}", err),
+            ErrorKind::IoError(err) => write!(f, "I/O error: {}", err),
+            ErrorKind::Git2Error(err) => write!(f, "git error: {}", err),
+            ErrorKind::VcsError(err) => write!(f, "{}", err),
+            ErrorKind::CurlError(err) => write!(f, "HTTP request failed: {}", err),
+            ErrorKind::CurlFormError(err) => write!(f, "could not build the upload form: {}", err),
+            ErrorKind::EnvironmentVariableNotFound(var) => {
+                write!(f, "environment variable {} is not set", var)
+            }
+            ErrorKind::SerdeJsonError(err) => write!(f, "could not parse JSON: {}", err),
+            ErrorKind::Utf8Error(err) => write!(f, "invalid UTF-8: {}", err),
+            ErrorKind::ActixWebError(err) => write!(f, "HTTP request failed: {}", err),
+            ErrorKind::JsonPayloadError(err) => {
+                write!(f, "could not parse the JSON response: {}", err)
+            }
+            ErrorKind::PayloadError(err) => write!(f, "could not read the response: {}", err),
+            ErrorKind::SendRequestError(err) => write!(f, "could not send the request: {}", err),
+            ErrorKind::TrustDnsResolveError(err) => write!(f, "DNS lookup failed: {}", err),
+            ErrorKind::IpfsDaemonUnreachable(addr) => write!(
+                f,
+                "the IPFS daemon at {} is unreachable, is it running?",
+                addr
+            ),
+            ErrorKind::IpfsApiError(code, message) => {
+                write!(f, "the IPFS API responded with {}: {}", code, message)
+            }
+            ErrorKind::NothingAddedToIpfs(path) => {
+                write!(f, "IPFS added nothing for {}", path.display())
+            }
+            ErrorKind::IpfsRefetchIncomplete(cid) => {
+                write!(f, "could not fetch every missing block of {} again", cid)
+            }
+            ErrorKind::TimerError(err) => write!(f, "timer error: {}", err),
+            ErrorKind::ContainerNotRunning(id) => write!(f, "container {} is not running", id),
+            ErrorKind::ContainerUnhealthy(id) => write!(f, "container {} is unhealthy", id),
+            ErrorKind::ContainerRenameFailed(id, name) => {
+                write!(f, "could not rename container {} to {}", id, name)
+            }
+            ErrorKind::SecretFileUnreadable(path, err) => write!(
+                f,
+                "could not read secret file {}: {}",
+                path.display(),
+                err
+            ),
+            ErrorKind::DockerBuildError(message) => write!(f, "docker build failed: {}", message),
+            ErrorKind::DockerApiError { status, body } => {
+                write!(f, "the Docker daemon responded with {}: {}", status, body)
+            }
+            ErrorKind::DockerPullError(message) => write!(f, "docker pull failed: {}", message),
+            ErrorKind::RuntimeCommandFailed(message) => {
+                write!(f, "runtime command failed: {}", message)
+            }
+            ErrorKind::GitCommandFailed(command, stderr) => {
+                write!(f, "git {} failed: {}", command, stderr)
+            }
+            ErrorKind::RemotePinNotConfigured => write!(
+                f,
+                "--pin-remote needs a [remote_pin] section in the config"
+            ),
+            ErrorKind::RemotePinRejected(code, body) => {
+                write!(f, "the remote pinning service responded with {}: {}", code, body)
+            }
+            ErrorKind::TempDirCreationFailed(root, err) => write!(
+                f,
+                "could not create a temporary directory in {}: {}",
+                root.display(),
+                err
+            ),
+            ErrorKind::InvalidDnsTtl(ttl) => write!(
+                f,
+                "DNS record TTL {} should be 1 for automatic or between 120 and 2147483647",
+                ttl
+            ),
+            ErrorKind::CloudflareApiError(errors) => {
+                write!(f, "Cloudflare rejected the request:")?;
+                for error in errors {
+                    write!(f, " {} ({})", error.message, error.code)?;
+                }
+                Ok(())
+            }
+            ErrorKind::UnknownDeployTarget(dns_record_name) => write!(
+                f,
+                "the daemon config has no target with dns_record_name {:?}",
+                dns_record_name
+            ),
+            ErrorKind::RollbackImageNotFound(deployment, Some(cid)) => write!(
+                f,
+                "there is no image of {} for {}, see lrad rollback --list",
+                deployment, cid
+            ),
+            ErrorKind::RollbackImageNotFound(deployment, None) => {
+                write!(f, "there is no earlier image of {} to roll back to", deployment)
+            }
+            ErrorKind::ConfigPathOutsideRepo(path) => write!(
+                f,
+                "config {} should be inside the repo, relative to its root",
+                path.display()
+            ),
+            ErrorKind::InvalidConfig(errors) => {
+                write!(f, "the config is invalid:")?;
+                for error in errors {
+                    write!(f, "\n  {}", error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl StdError for ErrorKind {
+    fn source(&self) -> Option<&(StdError + 'static)> {
+        match self {
+            ErrorKind::TomlSer(err) => Some(err),
+            ErrorKind::TomlDe(err) => Some(err),
+            ErrorKind::IoError(err) => Some(err),
+            ErrorKind::Git2Error(err) => Some(err),
+            ErrorKind::VcsError(err) => Some(err),
+            ErrorKind::CurlError(err) => Some(err),
+            ErrorKind::CurlFormError(err) => Some(err),
+            ErrorKind::SerdeJsonError(err) => Some(err),
+            ErrorKind::Utf8Error(err) => Some(err),
+            ErrorKind::TimerError(err) => Some(err),
+            ErrorKind::SecretFileUnreadable(_, err) => Some(err),
+            ErrorKind::TempDirCreationFailed(_, err) => Some(err),
+            // The actix-web and trust-dns errors only implement failure::Fail, and the rest don't
+            // wrap another error
+            _ => None,
+        }
+    }
 }
 
 pub type Error = Box<ErrorKind>;
@@ -120,3 +293,26 @@ impl From<ResolveError> for Error {
         Box::new(ErrorKind::TrustDnsResolveError(err))
     }
 }
+
+impl From<TimerError> for Error {
+    fn from(err: TimerError) -> Self {
+        Box::new(ErrorKind::TimerError(err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converted_error_keeps_its_source() {
+        let err: Error = Git2Error::from_str("reference 'refs/heads/master' not found").into();
+        let source = err.source().unwrap();
+        assert!(source.downcast_ref::<Git2Error>().is_some());
+        assert_eq!(source.to_string(), "reference 'refs/heads/master' not found");
+        assert_eq!(
+            err.to_string(),
+            "git error: reference 'refs/heads/master' not found"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use actix_web::{http::Method, server, App, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use crate::config::DeployTarget;
+use crate::error::Result;
+
+/// How a single target has been doing, as shown by `/status`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct TargetHealth {
+    pub dns_record_name: String,
+    /// Whether the last DNS lookup of the target succeeded, false until the first one finishes
+    pub lookup_succeeded: bool,
+    pub deployed_cid: Option<String>,
+    pub deployed_at: Option<DateTime<Utc>>,
+}
+
+/// What the daemon has seen of its targets so far, shared with the health endpoint.
+#[derive(Clone)]
+pub struct Health(Arc<Mutex<Vec<TargetHealth>>>);
+
+impl Health {
+    pub fn new(targets: &[DeployTarget]) -> Self {
+        Health(Arc::new(Mutex::new(
+            targets
+                .iter()
+                .map(|target| TargetHealth {
+                    dns_record_name: target.dns_record_name.clone(),
+                    lookup_succeeded: false,
+                    deployed_cid: None,
+                    deployed_at: None,
+                })
+                .collect(),
+        )))
+    }
+
+    pub fn lookup_finished(&self, target: usize, succeeded: bool) {
+        self.0.lock().unwrap()[target].lookup_succeeded = succeeded;
+    }
+
+    pub fn deployed(&self, target: usize, cid: Option<String>) {
+        let mut targets = self.0.lock().unwrap();
+        targets[target].deployed_cid = cid;
+        targets[target].deployed_at = Some(Utc::now());
+    }
+
+    /// Ready once the last DNS lookup of every target succeeded.
+    pub fn is_ready(&self) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .iter()
+            .all(|target| target.lookup_succeeded)
+    }
+
+    pub fn targets(&self) -> Vec<TargetHealth> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+fn healthz(_req: &HttpRequest<Health>) -> HttpResponse {
+    HttpResponse::Ok().body("ok")
+}
+
+fn readyz(req: &HttpRequest<Health>) -> HttpResponse {
+    if req.state().is_ready() {
+        HttpResponse::Ok().body("ready")
+    } else {
+        HttpResponse::ServiceUnavailable().body("not ready")
+    }
+}
+
+fn status(req: &HttpRequest<Health>) -> HttpResponse {
+    HttpResponse::Ok().json(req.state().targets())
+}
+
+/// Serves `/healthz`, `/readyz` and `/status` on `addr` from the running actix system.
+pub fn serve(addr: SocketAddr, health: Health) -> Result<()> {
+    server::new(move || {
+        App::with_state(health.clone())
+            .resource("/healthz", |r| r.method(Method::GET).f(healthz))
+            .resource("/readyz", |r| r.method(Method::GET).f(readyz))
+            .resource("/status", |r| r.method(Method::GET).f(status))
+    })
+    .workers(1)
+    // The daemon handles SIGTERM/SIGINT itself, letting deploys in progress finish first
+    .disable_signals()
+    .bind(addr)?
+    .start();
+    info!("Serving health checks on {}", addr);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_once_every_target_was_looked_up() {
+        let target = |dns_record_name: &str| TargetHealth {
+            dns_record_name: String::from(dns_record_name),
+            lookup_succeeded: false,
+            deployed_cid: None,
+            deployed_at: None,
+        };
+        let health = Health(Arc::new(Mutex::new(vec![
+            target("_dnslink.app.example.com"),
+            target("_dnslink.other.example.com"),
+        ])));
+        assert!(!health.is_ready());
+        health.lookup_finished(0, true);
+        assert!(!health.is_ready());
+        health.lookup_finished(1, true);
+        assert!(health.is_ready());
+        health.lookup_finished(0, false);
+        assert!(!health.is_ready());
+    }
+}
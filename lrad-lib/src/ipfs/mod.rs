@@ -1,12 +1,58 @@
-use crate::error::Result;
-use curl::easy::{Easy, Form};
+use crate::error::{Error, ErrorKind, Result};
+use curl::easy::{Easy, Form, List};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt;
 use std::path::Path;
 use std::path::PathBuf;
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Http,
+    Https,
+}
+
+impl Default for Scheme {
+    fn default() -> Self {
+        Scheme::Http
+    }
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Scheme::Http => write!(f, "http"),
+            Scheme::Https => write!(f, "https"),
+        }
+    }
+}
+
+/// Credentials for an IPFS API server behind a reverse proxy or hosted service.
+/// Secrets are read from environment variables, like the Cloudflare config.
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum IpfsAuth {
+    Basic {
+        username: String,
+        password_env_var: String,
+    },
+    Bearer {
+        token_env_var: String,
+    },
+}
+
+fn read_env_var(name: &str) -> Result<String> {
+    env::var(name).map_err(|_| ErrorKind::EnvironmentVariableNotFound(String::from(name)).into())
+}
+
+#[derive(Deserialize, Serialize, Clone)]
 pub struct IpfsApiServerConfig {
     host: String,
     port: u16,
+    #[serde(default)]
+    scheme: Scheme,
+    auth: Option<IpfsAuth>,
 }
 
 impl Default for IpfsApiServerConfig {
@@ -14,8 +60,304 @@ impl Default for IpfsApiServerConfig {
         Self {
             host: String::from("localhost"),
             port: 5001,
+            scheme: Scheme::default(),
+            auth: None,
+        }
+    }
+}
+
+impl IpfsApiServerConfig {
+    fn api_url(&self, endpoint: &str) -> String {
+        format!(
+            "{}://{}:{}/api/v0/{}",
+            self.scheme, self.host, self.port, endpoint
+        )
+    }
+
+    fn apply_auth(&self, handle: &mut Easy) -> Result<()> {
+        match &self.auth {
+            Some(IpfsAuth::Basic {
+                username,
+                password_env_var,
+            }) => {
+                handle.username(username)?;
+                handle.password(&read_env_var(password_env_var)?)?;
+            }
+            Some(IpfsAuth::Bearer { token_env_var }) => {
+                let mut headers = List::new();
+                headers.append(&format!(
+                    "Authorization: Bearer {}",
+                    read_env_var(token_env_var)?
+                ))?;
+                handle.http_headers(headers)?;
+            }
+            None => {}
+        }
+        Ok(())
+    }
+}
+
+fn perform(handle: &mut Easy) -> std::result::Result<Vec<u8>, curl::Error> {
+    let mut dst = Vec::new();
+    {
+        let mut transfer = handle.transfer();
+        transfer.write_function(|data| {
+            dst.extend_from_slice(data);
+            Ok(data.len())
+        })?;
+        transfer.perform()?;
+    }
+    Ok(dst)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct IpfsVersionInfo {
+    #[serde(rename = "Version")]
+    pub version: String,
+    #[serde(rename = "Commit")]
+    pub commit: Option<String>,
+    #[serde(rename = "Repo")]
+    pub repo: Option<String>,
+}
+
+/// Oldest go-ipfs release whose HTTP API lrad has been used against.
+const MIN_IPFS_VERSION: (u32, u32) = (0, 4);
+
+/// Checks that the IPFS API server is reachable, so that a push fails with a clear error
+/// instead of somewhere deep inside the add transfer.
+pub fn check_daemon(config: &IpfsApiServerConfig) -> Result<IpfsVersionInfo> {
+    let mut handle = Easy::new();
+    // go-ipfs 0.5+ only accepts POST on the API
+    handle.post(true)?;
+    handle.connect_timeout(std::time::Duration::from_secs(5))?;
+    handle.url(config.api_url("version").as_str())?;
+    config.apply_auth(&mut handle)?;
+    let dst = perform(&mut handle).map_err(|err| {
+        if err.is_couldnt_connect() || err.is_couldnt_resolve_host() || err.is_operation_timedout() {
+            ErrorKind::IpfsDaemonUnreachable(format!("{}:{}", config.host, config.port)).into()
+        } else {
+            crate::error::Error::from(err)
+        }
+    })?;
+    let version_info: IpfsVersionInfo = serde_json::from_slice(&dst)?;
+    debug!("IPFS daemon version is {}", version_info.version);
+    let mut version_parts = version_info
+        .version
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let major_minor = (
+        version_parts.next().unwrap_or(0),
+        version_parts.next().unwrap_or(0),
+    );
+    if major_minor < MIN_IPFS_VERSION {
+        warn!(
+            "IPFS daemon version {} is older than {}.{} and may not be compatible.",
+            version_info.version, MIN_IPFS_VERSION.0, MIN_IPFS_VERSION.1
+        );
+    }
+    Ok(version_info)
+}
+
+/// A remote pinning service speaking the IPFS Pinning Service API, so that pushed content stays
+/// available while the pushing machine is off.
+#[derive(Deserialize, Serialize)]
+pub struct RemotePinConfig {
+    /// e.g. "https://api.pinata.cloud/psa"
+    pub endpoint: String,
+    pub access_token_env_var: String,
+}
+
+#[derive(Serialize)]
+struct RemotePinRequest<'a> {
+    cid: &'a str,
+    name: &'a str,
+}
+
+#[derive(Deserialize)]
+struct RemotePinStatus {
+    requestid: String,
+    status: String,
+}
+
+/// Asks the remote pinning service to pin `cid` under `name`. The service fetches the content
+/// itself, so this returns once the request has been queued rather than once it is pinned.
+pub fn pin_remote(config: &RemotePinConfig, cid: &str, name: &str) -> Result<()> {
+    let body = serde_json::to_vec(&RemotePinRequest { cid, name })?;
+    let mut handle = Easy::new();
+    handle.post(true)?;
+    handle.url(&format!("{}/pins", config.endpoint.trim_end_matches('/')))?;
+    let mut headers = List::new();
+    headers.append(&format!(
+        "Authorization: Bearer {}",
+        read_env_var(&config.access_token_env_var)?
+    ))?;
+    headers.append("Content-Type: application/json")?;
+    handle.http_headers(headers)?;
+    handle.post_fields_copy(&body)?;
+    let dst = perform(&mut handle)?;
+    let response_code = handle.response_code()?;
+    if response_code != 200 && response_code != 202 {
+        return Err(ErrorKind::RemotePinRejected(
+            response_code,
+            String::from_utf8_lossy(&dst).into_owned(),
+        )
+        .into());
+    }
+    let pin_status: RemotePinStatus = serde_json::from_slice(&dst)?;
+    info!(
+        "Remote pin request {} is {}",
+        pin_status.requestid, pin_status.status
+    );
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct IpfsRefResponse {
+    #[serde(rename = "Err")]
+    err: String,
+}
+
+/// Whether a recursive refs listing completed without any per-block errors.
+fn refs_complete(body: &str) -> Result<bool> {
+    for line in body.split_terminator('\n') {
+        let response: IpfsRefResponse = serde_json::from_str(line)?;
+        if !response.err.is_empty() {
+            debug!("Missing or invalid block: {}", response.err);
+            return Ok(false);
         }
     }
+    Ok(true)
+}
+
+/// Lists every block of `cid` recursively, returning whether all of them could be read.
+fn refs(config: &IpfsApiServerConfig, cid: &str, offline: bool) -> Result<bool> {
+    let dst = call(
+        config,
+        &format!("refs?arg={}&recursive=true&offline={}", cid, offline),
+    )?;
+    refs_complete(std::str::from_utf8(dst.as_slice())?)
+}
+
+/// Re-verifies that every block of `cid` is present in the local IPFS store without touching the
+/// network, re-fetching the content if any are missing or corrupt. Returns whether a re-fetch
+/// was needed, failing if it couldn't get every block back.
+pub fn verify_or_refetch(config: &IpfsApiServerConfig, cid: &str) -> Result<bool> {
+    if refs(config, cid, true)? {
+        return Ok(false);
+    }
+    warn!("Local IPFS store is missing blocks of {}, re-fetching", cid);
+    if !refs(config, cid, false)? {
+        return Err(ErrorKind::IpfsRefetchIncomplete(String::from(cid)).into());
+    }
+    Ok(true)
+}
+
+#[derive(Deserialize)]
+struct IpfsErrorResponse {
+    #[serde(rename = "Message")]
+    message: String,
+}
+
+/// Calls `endpoint` on the IPFS API server, turning an error response into an `IpfsApiError`.
+fn call(config: &IpfsApiServerConfig, endpoint: &str) -> Result<Vec<u8>> {
+    let mut handle = Easy::new();
+    handle.post(true)?;
+    handle.url(config.api_url(endpoint).as_str())?;
+    config.apply_auth(&mut handle)?;
+    let dst = perform(&mut handle)?;
+    let response_code = handle.response_code()?;
+    if response_code != 200 {
+        let message = serde_json::from_slice::<IpfsErrorResponse>(&dst)
+            .map(|err| err.message)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&dst).into_owned());
+        return Err(ErrorKind::IpfsApiError(response_code, message).into());
+    }
+    Ok(dst)
+}
+
+#[derive(Deserialize)]
+struct IpnsPublishResponse {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Value")]
+    value: String,
+}
+
+/// Points the IPNS name of the IPFS node's `key` at `cid`, returning the name.
+pub fn publish_name(config: &IpfsApiServerConfig, key: &str, cid: &str) -> Result<String> {
+    let dst = call(config, &format!("name/publish?arg=/ipfs/{}&key={}", cid, key))?;
+    let published: IpnsPublishResponse = serde_json::from_slice(&dst)?;
+    debug!("Published {} to {}", published.value, published.name);
+    Ok(published.name)
+}
+
+#[derive(Deserialize)]
+struct IpnsResolveResponse {
+    #[serde(rename = "Path")]
+    path: String,
+}
+
+/// The CID that the IPNS `name` currently points at.
+pub fn resolve_name(config: &IpfsApiServerConfig, name: &str) -> Result<String> {
+    let dst = call(config, &format!("name/resolve?arg={}", name))?;
+    let resolved: IpnsResolveResponse = serde_json::from_slice(&dst)?;
+    let cid = resolved.path.trim_start_matches("/ipfs/");
+    Ok(String::from(cid))
+}
+
+#[derive(Deserialize)]
+struct IpfsObjectResponse {
+    #[serde(rename = "Hash")]
+    hash: String,
+}
+
+/// Links `cid` into the directory `root` at `path`, creating missing directories along the way.
+/// Returns the new root, `root` itself stays as it is.
+pub fn patch_add_link(
+    config: &IpfsApiServerConfig,
+    root: &str,
+    path: &Path,
+    cid: &str,
+) -> Result<String> {
+    let dst = call(
+        config,
+        &format!(
+            "object/patch/add-link?arg={}&arg={}&arg={}&create=true",
+            root,
+            path.display(),
+            cid
+        ),
+    )?;
+    Ok(serde_json::from_slice::<IpfsObjectResponse>(&dst)?.hash)
+}
+
+/// Unlinks `path` from the directory `root`, returning the new root.
+pub fn patch_rm_link(config: &IpfsApiServerConfig, root: &str, path: &Path) -> Result<String> {
+    let dst = call(
+        config,
+        &format!("object/patch/rm-link?arg={}&arg={}", root, path.display()),
+    )?;
+    Ok(serde_json::from_slice::<IpfsObjectResponse>(&dst)?.hash)
+}
+
+/// Pins `cid` recursively, e.g. a root put together with `patch_add_link` rather than added.
+pub fn pin_add(config: &IpfsApiServerConfig, cid: &str) -> Result<()> {
+    call(config, &format!("pin/add?arg={}&recursive=true", cid))?;
+    Ok(())
+}
+
+/// Downloads what `path`, e.g. "/ipns/example.com", points at into the directory `into`, named
+/// after the last component of `path`.
+pub fn get(config: &IpfsApiServerConfig, path: &str, into: &Path) -> Result<()> {
+    let dst = call(config, &format!("get?arg={}&archive=true", path))?;
+    tar::Archive::new(dst.as_slice()).unpack(into)?;
+    Ok(())
+}
+
+/// Unpins `cid`, letting the IPFS node garbage collect it unless something else still pins it.
+pub fn pin_rm(config: &IpfsApiServerConfig, cid: &str) -> Result<()> {
+    call(config, &format!("pin/rm?arg={}&recursive=true", cid))?;
+    Ok(())
 }
 
 #[derive(Serialize)]
@@ -24,9 +366,24 @@ pub struct IpfsAddRecursive<'a> {
     pub recursive: Option<bool>,
     #[serde(rename = "wrap-with-directory")]
     pub wrap_with_directory: Option<bool>,
+    /// Only add these files, given relative to `path`
+    #[serde(skip)]
+    only: Option<HashSet<PathBuf>>,
     config: &'a IpfsApiServerConfig,
 }
 
+#[derive(Deserialize)]
+struct IpfsAddStreamObject {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Hash")]
+    hash: Option<String>,
+    #[serde(rename = "Bytes")]
+    bytes: Option<u64>,
+    #[serde(rename = "Size")]
+    size: Option<String>,
+}
+
 #[derive(Deserialize, Debug)]
 pub struct IpfsAddResponse {
     #[serde(rename = "Name")]
@@ -45,76 +402,338 @@ impl<'a> IpfsAddRecursive<'a> {
             path: PathBuf::from(path),
             recursive: Some(true),
             wrap_with_directory: None,
+            only: None,
             config,
         }
     }
 
+    /// Adds only `files`, given relative to the path, e.g. the ones that changed since the last
+    /// push. The directories they are in are added with just those files.
+    pub fn only(mut self, files: Vec<PathBuf>) -> Self {
+        self.only = Some(files.into_iter().collect());
+        self
+    }
+
+    /// The files that would be added, relative to the path.
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        self.walk_dir(&self.path, &self.path, &mut files, &mut 0)?;
+        Ok(files.into_iter().map(|(_, relative)| relative).collect())
+    }
+
+    /// Adds the files, logging upload progress as it goes.
     pub fn run(&self) -> Result<Vec<IpfsAddResponse>> {
+        let mut last_percent = None;
+        self.run_with_progress(|bytes_sent, bytes_total| {
+            let percent = if bytes_total == 0 {
+                100
+            } else {
+                bytes_sent * 100 / bytes_total
+            };
+            if last_percent != Some(percent) {
+                info!(
+                    "Added {}/{} bytes to IPFS ({}%)",
+                    bytes_sent, bytes_total, percent
+                );
+                last_percent = Some(percent);
+            }
+        })
+    }
+
+    /// Adds the files, invoking `on_progress(bytes_sent, bytes_total)` whenever IPFS reports
+    /// progress.
+    pub fn run_with_progress<F: FnMut(u64, u64)>(
+        &self,
+        mut on_progress: F,
+    ) -> Result<Vec<IpfsAddResponse>> {
         let mut handle = Easy::new();
         handle.post(true)?;
         handle.url(
-            format!(
-                "http://{}:{}/api/v0/add?recursive={}",
-                self.config.host,
-                self.config.port,
-                self.recursive.unwrap_or(false)
-            )
-            .as_str(),
+            self.config
+                .api_url(&format!(
+                    "add?recursive={}&progress=true",
+                    self.recursive.unwrap_or(false)
+                ))
+                .as_str(),
         )?;
+        self.config.apply_auth(&mut handle)?;
         let mut form = Form::new();
+        let mut bytes_total = 0;
         debug!("Walking directory");
-        Self::walk_dir_to_form(&self.path.parent().unwrap(), &self.path, &mut form)?;
-        debug!("Done walking directory");
+        let mut files = Vec::new();
+        self.walk_dir(
+            &self.path.parent().unwrap(),
+            &self.path,
+            &mut files,
+            &mut bytes_total,
+        )?;
+        for (absolute_entry_path, relative_entry_path) in &files {
+            let mut part = form.part("path");
+            part.file(absolute_entry_path);
+            part.filename(relative_entry_path.to_str().unwrap());
+            part.add()?;
+        }
+        debug!("Done walking directory, {} bytes to add", bytes_total);
         handle.httppost(form)?;
-        let mut dst = Vec::new();
-        {
+        // IPFS streams newline-delimited JSON objects, so parse them as they arrive.
+        // Progress objects only carry Name and Bytes, the final object for each file has a Hash.
+        let mut responses: Vec<IpfsAddResponse> = Vec::new();
+        let mut bytes_per_file: HashMap<String, u64> = HashMap::new();
+        let mut pending = Vec::new();
+        let mut parse_err: Option<Error> = None;
+        let perform_res = {
             let mut transfer = handle.transfer();
             transfer.write_function(|data| {
-                dst.extend_from_slice(data);
+                pending.extend_from_slice(data);
+                while let Some(newline) = pending.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=newline).collect();
+                    let object: IpfsAddStreamObject = match serde_json::from_slice(&line) {
+                        Ok(object) => object,
+                        Err(err) => {
+                            parse_err = Some(err.into());
+                            return Ok(0);
+                        }
+                    };
+                    match (object.hash, object.size) {
+                        (Some(hash), Some(size)) => responses.push(IpfsAddResponse {
+                            name: object.name,
+                            hash,
+                            bytes: object.bytes.map(|bytes| bytes as usize),
+                            size,
+                        }),
+                        _ => {
+                            bytes_per_file.insert(object.name, object.bytes.unwrap_or(0));
+                            on_progress(bytes_per_file.values().sum(), bytes_total);
+                        }
+                    }
+                }
                 Ok(data.len())
             })?;
-            transfer.perform()?;
-        }
-        // TODO: IPFS outputs invalid JSON, confirmed! A list of {}s is invalid
-        // For now, unsafely converting this to valid JSON because the outptu of IPFS
-        // is trusted.
-        let dst_str = String::from(std::str::from_utf8(dst.as_slice())?);
-        let mut responses: Vec<IpfsAddResponse> = Vec::new();
-        for response in dst_str.split_terminator('\n') {
-            responses.push(serde_json::from_str(&response)?);
+            transfer.perform()
+        };
+        if let Some(err) = parse_err {
+            return Err(err);
         }
+        perform_res?;
         responses
             .iter()
             .for_each(|res| debug!("Received response {:?}", res));
         Ok(responses)
     }
 
-    fn walk_dir_to_form(root: &Path, path: &Path, form: &mut Form) -> Result<()> {
+    /// Collects the files to add under `path`, as absolute paths and paths relative to `root`.
+    /// Entries are sorted by name so that the same tree always produces the same form.
+    fn walk_dir(
+        &self,
+        root: &Path,
+        path: &Path,
+        files: &mut Vec<(PathBuf, PathBuf)>,
+        bytes_total: &mut u64,
+    ) -> Result<()> {
         if !path.is_dir() {
             return Ok(());
         }
-        for entry in path.read_dir()? {
-            let entry = entry?;
+        let mut entries = path
+            .read_dir()?
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
             let absolute_entry_path = entry.path();
             if absolute_entry_path.is_dir() {
                 debug!(
                     "Looking at directory {}",
                     absolute_entry_path.to_str().unwrap()
                 );
-                Self::walk_dir_to_form(root, &absolute_entry_path, form)?;
+                self.walk_dir(root, &absolute_entry_path, files, bytes_total)?;
             } else {
-                let path_buf: PathBuf = absolute_entry_path.to_path_buf();
-                let relative_entry_path = path_buf.strip_prefix(root).unwrap();
+                if let Some(only) = &self.only {
+                    if !only.contains(absolute_entry_path.strip_prefix(&self.path).unwrap()) {
+                        continue;
+                    }
+                }
+                let relative_entry_path =
+                    absolute_entry_path.strip_prefix(root).unwrap().to_path_buf();
                 debug!(
                     "Adding file {} to send to IPFS",
                     relative_entry_path.to_str().unwrap()
                 );
-                let mut part = form.part("path");
-                part.file(&absolute_entry_path);
-                part.filename(relative_entry_path.to_str().unwrap());
-                part.add()?;
+                *bytes_total += entry.metadata()?.len();
+                files.push((absolute_entry_path, relative_entry_path));
             }
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{serve, serve_once};
+
+    #[test]
+    fn walk_order_is_reproducible() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        for name in &["b", "a/z", "a/c", "C", "d"] {
+            let path = repo.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        let config = IpfsApiServerConfig::default();
+        let add = IpfsAddRecursive::new(&config, &repo);
+        let walk = || {
+            let mut files = Vec::new();
+            add.walk_dir(dir.path(), &repo, &mut files, &mut 0).unwrap();
+            files
+                .into_iter()
+                .map(|(_, relative)| relative)
+                .collect::<Vec<_>>()
+        };
+        let first = walk();
+        assert_eq!(first, walk());
+        let expected: Vec<PathBuf> = ["repo/C", "repo/a/c", "repo/a/z", "repo/b", "repo/d"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(first, expected);
+    }
+
+    #[test]
+    fn pin_remote_posts_cid_with_token() {
+        let (addr, server) = serve_once(
+            "HTTP/1.1 202 Accepted\r\nContent-Type: application/json\r\nContent-Length: 36\r\n\r\n{\"requestid\":\"r1\",\"status\":\"queued\"}",
+        );
+        env::set_var("LRAD_TEST_PIN_TOKEN", "secret");
+        let config = RemotePinConfig {
+            endpoint: format!("http://{}", addr),
+            access_token_env_var: String::from("LRAD_TEST_PIN_TOKEN"),
+        };
+        pin_remote(&config, "QmTest", "app").unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /pins HTTP/1.1\r\n"));
+        assert!(request.contains("Authorization: Bearer secret\r\n"));
+        assert!(request.ends_with("{\"cid\":\"QmTest\",\"name\":\"app\"}"));
+    }
+
+    fn config_for(addr: &str) -> IpfsApiServerConfig {
+        IpfsApiServerConfig {
+            host: String::from("127.0.0.1"),
+            port: addr.rsplit(':').next().unwrap().parse().unwrap(),
+            ..IpfsApiServerConfig::default()
+        }
+    }
+
+    #[test]
+    fn published_name_resolves_to_cid() {
+        let (addr, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 40\r\n\r\n{\"Name\":\"k51app\",\"Value\":\"/ipfs/QmTest\"}",
+        );
+        let name = publish_name(&config_for(&addr), "app", "QmTest").unwrap();
+        assert_eq!(name, "k51app");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v0/name/publish?arg=/ipfs/QmTest&key=app HTTP/1.1\r\n"));
+
+        let (addr, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 23\r\n\r\n{\"Path\":\"/ipfs/QmTest\"}",
+        );
+        assert_eq!(resolve_name(&config_for(&addr), &name).unwrap(), "QmTest");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v0/name/resolve?arg=k51app HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn patched_root_links_added_file() {
+        let (addr, server) = serve_once(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 20\r\n\r\n{\"Hash\":\"QmNewRoot\"}",
+        );
+        let root = patch_add_link(
+            &config_for(&addr),
+            "QmOldRoot",
+            Path::new("objects/ab/cdef"),
+            "QmObject",
+        )
+        .unwrap();
+        assert_eq!(root, "QmNewRoot");
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /api/v0/object/patch/add-link?arg=QmOldRoot&arg=objects/ab/cdef&arg=QmObject&create=true HTTP/1.1\r\n"));
+    }
+
+    #[test]
+    fn only_given_files_are_added() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("repo");
+        for name in &["HEAD", "objects/ab/cdef", "objects/12/3456"] {
+            let path = repo.join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, name).unwrap();
+        }
+        let config = IpfsApiServerConfig::default();
+        let add = IpfsAddRecursive::new(&config, &repo);
+        assert_eq!(add.files().unwrap().len(), 3);
+        let add = add.only(vec![PathBuf::from("HEAD"), PathBuf::from("objects/12/3456")]);
+        let mut files = Vec::new();
+        let mut bytes_total = 0;
+        add.walk_dir(dir.path(), &repo, &mut files, &mut bytes_total)
+            .unwrap();
+        let sent: Vec<PathBuf> = files.into_iter().map(|(_, relative)| relative).collect();
+        assert_eq!(
+            sent,
+            vec![PathBuf::from("repo/HEAD"), PathBuf::from("repo/objects/12/3456")]
+        );
+        assert_eq!(bytes_total, ("HEAD".len() + "objects/12/3456".len()) as u64);
+    }
+
+    #[test]
+    fn ipfs_api_errors_carry_the_message() {
+        let (addr, server) = serve_once(
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Type: application/json\r\nContent-Length: 45\r\n\r\n{\"Message\":\"no key named app\",\"Type\":\"error\"}",
+        );
+        match publish_name(&config_for(&addr), "app", "QmTest") {
+            Err(box ErrorKind::IpfsApiError(500, message)) => assert_eq!(message, "no key named app"),
+            _ => panic!("expected an IpfsApiError"),
+        }
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn refs_complete_detects_missing_block() {
+        let ok = "{\"Ref\":\"QmA\",\"Err\":\"\"}\n{\"Ref\":\"QmB\",\"Err\":\"\"}\n";
+        assert!(refs_complete(ok).unwrap());
+        let missing = "{\"Ref\":\"QmA\",\"Err\":\"\"}\n{\"Ref\":\"\",\"Err\":\"block was not found locally (offline)\"}\n";
+        assert!(!refs_complete(missing).unwrap());
+    }
+
+    #[test]
+    fn missing_blocks_are_refetched() {
+        let (addr, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 80\r\n\r\n{\"Ref\":\"QmA\",\"Err\":\"\"}\n{\"Ref\":\"\",\"Err\":\"block was not found locally (offline)\"}\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 46\r\n\r\n{\"Ref\":\"QmA\",\"Err\":\"\"}\n{\"Ref\":\"QmA\",\"Err\":\"\"}\n",
+        ]);
+        assert!(verify_or_refetch(&config_for(&addr), "QmTest").unwrap());
+        let requests = server.join().unwrap();
+        assert!(requests[0].starts_with("POST /api/v0/refs?arg=QmTest&recursive=true&offline=true "));
+        assert!(requests[1].starts_with("POST /api/v0/refs?arg=QmTest&recursive=true&offline=false "));
+
+        let (addr, server) = serve(vec![
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 57\r\n\r\n{\"Ref\":\"\",\"Err\":\"block was not found locally (offline)\"}\n",
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: 45\r\n\r\n{\"Ref\":\"\",\"Err\":\"context deadline exceeded\"}\n",
+        ]);
+        match verify_or_refetch(&config_for(&addr), "QmTest") {
+            Err(box ErrorKind::IpfsRefetchIncomplete(cid)) => assert_eq!(cid, "QmTest"),
+            other => panic!("expected IpfsRefetchIncomplete, got {:?}", other),
+        }
+        server.join().unwrap();
+
+        let (addr, server) = serve_once(
+            "HTTP/1.1 401 Unauthorized\r\nContent-Type: application/json\r\nContent-Length: 41\r\n\r\n{\"Message\":\"unauthorized\",\"Type\":\"error\"}",
+        );
+        match verify_or_refetch(&config_for(&addr), "QmTest") {
+            Err(box ErrorKind::IpfsApiError(code, message)) => {
+                assert_eq!(code, 401);
+                assert_eq!(message, "unauthorized");
+            }
+            other => panic!("expected an IpfsApiError, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
+}
@@ -8,31 +8,986 @@ extern crate log;
 
 use crate::dns::DnsRecordPutter;
 use futures::prelude::*;
+use futures::sync::oneshot;
 use futures::{future, stream};
-use git2::{DiffOptions, Repository, RepositoryState};
-use std::collections::HashMap;
+use git2::{DiffOptions, Repository};
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::Write;
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::process::{Command, Stdio};
+use std::cell::RefCell;
 use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
 use tempfile::TempDir;
 
+pub mod actor;
 pub mod config;
 pub mod dns;
 mod docker;
 pub mod error;
+mod health;
 mod ipfs;
+pub mod notify;
+mod retry;
+mod runtime;
+#[cfg(test)]
+mod test_server;
 mod vcs;
 
 pub use self::dns::DnsTxtRecordResponse;
-use self::docker::HostConfig;
-use self::error::{BoxFuture, Error, Result};
+use self::docker::{CreateContainerRequest, Docker, HostConfig, RetryPolicy};
+use self::error::{BoxFuture, Error, ErrorKind, Result};
+pub use self::runtime::DeployEvent;
+use self::runtime::{CommandRuntime, ContainerRuntime, ObservedRuntime};
 
 #[cfg(test)]
 mod tests {
+    use super::*;
+
     #[test]
     fn it_works() {
         assert_eq!(2 + 2, 4);
     }
+
+    fn container(id: &str, image_id: &str, deployment: Option<&str>) -> docker::ListContainersResponse {
+        docker::ListContainersResponse {
+            id: String::from(id),
+            image: String::from(image_id),
+            image_id: String::from(image_id),
+            labels: deployment.map(|deployment| {
+                let mut labels = HashMap::new();
+                labels.insert(String::from(docker::DEPLOYMENT_LABEL), String::from(deployment));
+                labels
+            }),
+            state: String::from("running"),
+        }
+    }
+
+    #[test]
+    fn unrelated_containers_survive_deploy() {
+        let containers = vec![
+            container("new", "sha256:new", Some("app.lrad.io")),
+            container("old", "sha256:old", Some("app.lrad.io")),
+            container("old-unlabeled", "sha256:tagged", None),
+            container("other-app", "sha256:other", Some("other.lrad.io")),
+            container("unrelated", "sha256:postgres", None),
+        ];
+        let removable_image_ids = vec![String::from("sha256:tagged")];
+        let removed: Vec<String> =
+            old_deployment_containers(containers, "new", "app.lrad.io", &removable_image_ids)
+                .into_iter()
+                .map(|container| container.id)
+                .collect();
+        assert_eq!(removed, vec!["old", "old-unlabeled"]);
+    }
+
+    fn image(created: i64, repo_tags: &[&str]) -> docker::ListImagesResponse {
+        docker::ListImagesResponse {
+            id: format!("sha256:{}", created),
+            repo_tags: repo_tags.iter().map(|tag| String::from(*tag)).collect(),
+            containers: 0,
+            created,
+        }
+    }
+
+    #[test]
+    fn rollback_goes_to_the_image_before_the_running_one() {
+        let tags = cid_tags(
+            vec![
+                image(1, &["app:QmOldest"]),
+                image(3, &["app:QmNewest", "app:latest"]),
+                image(2, &["app:QmMiddle", "app:QmMiddleAgain"]),
+            ],
+            "app",
+        );
+        assert_eq!(
+            rollback_tag(&tags, Some("sha256:3"), "app", None),
+            Some(String::from("app:QmMiddle"))
+        );
+        assert_eq!(
+            rollback_tag(&tags, Some("sha256:2"), "app", None),
+            Some(String::from("app:QmOldest"))
+        );
+        assert_eq!(rollback_tag(&tags, Some("sha256:1"), "app", None), None);
+        assert_eq!(
+            rollback_tag(&tags, None, "app", Some("QmOldest")),
+            Some(String::from("app:QmOldest"))
+        );
+        assert_eq!(rollback_tag(&tags, None, "app", Some("QmUnknown")), None);
+    }
+
+    #[test]
+    fn status_compares_the_running_image_to_the_record() {
+        let images = || vec![image(1, &["app:QmOld"]), image(2, &["app:QmNew", "app:latest"])];
+        let status = |published: Option<&str>, containers| {
+            deploy_status(
+                String::from("_dnslink.app"),
+                "app",
+                published.map(String::from),
+                containers,
+                images(),
+            )
+        };
+        let up_to_date = status(Some("QmNew"), vec![container("new", "sha256:2", Some("app"))]);
+        assert_eq!(up_to_date.state, DeployState::UpToDate);
+        assert_eq!(up_to_date.deployed_cid, Some(String::from("QmNew")));
+        let behind = status(Some("QmNewer"), vec![container("new", "sha256:2", Some("app"))]);
+        assert_eq!(behind.state, DeployState::Behind);
+        let failed = status(Some("QmNew"), vec![container("unrelated", "sha256:9", None)]);
+        assert_eq!(failed.state, DeployState::Failed);
+        assert_eq!(failed.deployed_cid, None);
+        let no_record = status(None, vec![container("new", "sha256:2", Some("app"))]);
+        assert_eq!(no_record.state, DeployState::NoRecord);
+        assert_eq!(no_record.deployed_cid, Some(String::from("QmNew")));
+    }
+
+    #[test]
+    fn only_oldest_cid_tags_are_stale() {
+        let images = vec![
+            image(1, &["app:QmOldest"]),
+            image(3, &["app:QmNewest", "app:latest"]),
+            image(2, &["app:QmMiddle", "other:QmMiddle"]),
+        ];
+        assert_eq!(stale_cid_tags(images, "app", 2), vec![String::from("app:QmOldest")]);
+    }
+
+    /// Records the calls made to it and answers with a fixed set of containers and images, all of
+    /// which are running and healthy.
+    #[derive(Default)]
+    struct MockRuntime {
+        calls: std::cell::RefCell<Vec<String>>,
+        /// What to answer the first start calls with, after which containers start fine
+        starts: std::cell::RefCell<Vec<bool>>,
+        renames_fail: bool,
+        /// Report containers as unhealthy instead
+        unhealthy: bool,
+    }
+
+    impl MockRuntime {
+        fn record(&self, call: String) {
+            self.calls.borrow_mut().push(call);
+        }
+    }
+
+    impl ContainerRuntime for MockRuntime {
+        fn build(&self, _context: PathBuf, image_name: String) -> BoxFuture<bool> {
+            self.record(format!("build {}", image_name));
+            Box::new(future::ok(true))
+        }
+
+        fn pull(&self, image_ref: String) -> BoxFuture<bool> {
+            self.record(format!("pull {}", image_ref));
+            Box::new(future::ok(true))
+        }
+
+        fn create(&self, _name: Option<String>, request: CreateContainerRequest) -> BoxFuture<String> {
+            self.record(format!("create {}", request.image));
+            Box::new(future::ok(String::from("new")))
+        }
+
+        fn start(&self, container_id: String) -> BoxFuture<bool> {
+            self.record(format!("start {}", container_id));
+            let mut starts = self.starts.borrow_mut();
+            let started = if starts.is_empty() { true } else { starts.remove(0) };
+            Box::new(future::ok(started))
+        }
+
+        fn inspect(&self, container_id: String) -> BoxFuture<docker::InspectContainerResponse> {
+            self.record(format!("inspect {}", container_id));
+            Box::new(future::ok(docker::InspectContainerResponse {
+                id: container_id,
+                state: docker::ContainerState {
+                    running: true,
+                    health: Some(docker::ContainerHealth {
+                        status: String::from(if self.unhealthy { "unhealthy" } else { "healthy" }),
+                    }),
+                },
+            }))
+        }
+
+        fn rename(&self, container_id: String, container_name: String) -> BoxFuture<bool> {
+            self.record(format!("rename {} {}", container_id, container_name));
+            Box::new(future::ok(!self.renames_fail))
+        }
+
+        fn remove(&self, container_id: String) -> BoxFuture<bool> {
+            self.record(format!("remove {}", container_id));
+            Box::new(future::ok(true))
+        }
+
+        fn list(&self) -> BoxFuture<Vec<docker::ListContainersResponse>> {
+            self.record(String::from("list"));
+            Box::new(future::ok(vec![
+                container("new", "sha256:2", Some("app")),
+                container("old", "sha256:1", Some("app")),
+                container("unrelated", "sha256:9", None),
+            ]))
+        }
+
+        fn list_images(&self) -> BoxFuture<Vec<docker::ListImagesResponse>> {
+            self.record(String::from("list_images"));
+            Box::new(future::ok(vec![
+                image(1, &["app:QmOld"]),
+                image(2, &["app:QmNew", "app:latest"]),
+            ]))
+        }
+
+        fn tag(&self, image: String, repo: String, tag: String) -> BoxFuture<bool> {
+            self.record(format!("tag {} {}:{}", image, repo, tag));
+            Box::new(future::ok(true))
+        }
+
+        fn remove_image(&self, image: String) -> BoxFuture<bool> {
+            self.record(format!("remove_image {}", image));
+            Box::new(future::ok(true))
+        }
+    }
+
+    #[test]
+    fn cleanup_goes_through_the_runtime_in_order() {
+        let runtime = Rc::new(MockRuntime::default());
+        remove_old_containers(
+            runtime.clone(),
+            String::from("new"),
+            String::from("app:latest"),
+            String::from("app"),
+            1,
+        )
+        .wait()
+        .unwrap();
+        prune_cid_tags(runtime.clone(), String::from("app"), 1)
+            .wait()
+            .unwrap();
+        assert_eq!(
+            *runtime.calls.borrow(),
+            vec!["list_images", "list", "remove old", "list_images", "remove_image app:QmOld"]
+        );
+    }
+
+    fn replace(runtime: Rc<MockRuntime>) -> Result<bool> {
+        let request = CreateContainerRequest {
+            image: String::from("app:latest"),
+            labels: HashMap::new(),
+            env: Vec::new(),
+            host_config: None,
+        };
+        actix::System::new("lrad-test").block_on(replace_container(
+            runtime,
+            request,
+            String::from("app"),
+            1,
+            Duration::from_secs(5),
+        ))
+    }
+
+    #[test]
+    fn replace_swaps_containers_once_the_new_one_is_healthy() {
+        let runtime = Rc::new(MockRuntime::default());
+        assert!(replace(runtime.clone()).unwrap());
+        assert_eq!(
+            *runtime.calls.borrow(),
+            vec![
+                "remove app-next",
+                "create app:latest",
+                "start new",
+                "inspect new",
+                "list_images",
+                "list",
+                "remove old",
+                "rename new app",
+            ]
+        );
+    }
+
+    #[test]
+    fn replace_keeps_the_old_container_when_the_new_one_cannot_start() {
+        let runtime = Rc::new(MockRuntime {
+            starts: RefCell::new(vec![false]),
+            ..MockRuntime::default()
+        });
+        match replace(runtime.clone()) {
+            Err(box ErrorKind::ContainerNotRunning(id)) => assert_eq!(id, "new"),
+            other => panic!("expected ContainerNotRunning, got {:?}", other),
+        }
+        assert_eq!(
+            *runtime.calls.borrow(),
+            vec!["remove app-next", "create app:latest", "start new", "remove new"]
+        );
+
+        let runtime = Rc::new(MockRuntime {
+            renames_fail: true,
+            ..MockRuntime::default()
+        });
+        match replace(runtime) {
+            Err(box ErrorKind::ContainerRenameFailed(id, name)) => {
+                assert_eq!((id.as_str(), name.as_str()), ("new", "app"))
+            }
+            other => panic!("expected ContainerRenameFailed, got {:?}", other),
+        }
+    }
+
+    fn recreate(runtime: Rc<MockRuntime>) -> Result<bool> {
+        let request = CreateContainerRequest {
+            image: String::from("app:latest"),
+            labels: HashMap::new(),
+            env: Vec::new(),
+            host_config: None,
+        };
+        actix::System::new("lrad-test").block_on(recreate_container(
+            runtime,
+            request,
+            String::from("app"),
+            1,
+            Duration::from_secs(5),
+        ))
+    }
+
+    #[test]
+    fn recreate_removes_a_new_container_that_fails_after_the_old_ones_are_gone() {
+        let runtime = Rc::new(MockRuntime {
+            starts: RefCell::new(vec![false, false]),
+            ..MockRuntime::default()
+        });
+        match recreate(runtime.clone()) {
+            Err(box ErrorKind::ContainerNotRunning(id)) => assert_eq!(id, "new"),
+            other => panic!("expected ContainerNotRunning, got {:?}", other),
+        }
+        assert_eq!(
+            *runtime.calls.borrow(),
+            vec![
+                "create app:latest",
+                "start new",
+                "list_images",
+                "list",
+                "remove old",
+                "start new",
+                "remove new",
+            ]
+        );
+
+        let runtime = Rc::new(MockRuntime {
+            starts: RefCell::new(vec![false]),
+            unhealthy: true,
+            ..MockRuntime::default()
+        });
+        match recreate(runtime.clone()) {
+            Err(box ErrorKind::ContainerUnhealthy(id)) => assert_eq!(id, "new"),
+            other => panic!("expected ContainerUnhealthy, got {:?}", other),
+        }
+        assert_eq!(runtime.calls.borrow().last().unwrap(), "remove new");
+    }
+
+    #[test]
+    fn published_tree_is_compared_to_the_local_head() {
+        let repo_dir = TempDir::new().unwrap();
+        let repo = Repository::init(repo_dir.path()).unwrap();
+        let sig = git2::Signature::now("lrad", "lrad@example.com").unwrap();
+        let empty_tree = repo.index().unwrap().write_tree().unwrap();
+        let tree = repo.find_tree(empty_tree).unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+            .unwrap();
+        let published_tree = head_tree(repo_dir.path()).unwrap();
+        assert_eq!(published_tree, empty_tree);
+        match verification(String::from("QmTest"), published_tree, empty_tree) {
+            Verification::Match { cid, .. } => assert_eq!(cid, "QmTest"),
+            other => panic!("expected a match, got {:?}", other),
+        }
+        let other_tree = git2::Oid::from_str("0123456789abcdef0123456789abcdef01234567").unwrap();
+        assert_ne!(other_tree, empty_tree);
+        match verification(String::from("QmTest"), other_tree, empty_tree) {
+            Verification::Mismatch { .. } => {}
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn scaffolding_keeps_existing_files() {
+        let workdir = TempDir::new().unwrap();
+        std::fs::write(workdir.path().join("Dockerfile"), "FROM scratch\n").unwrap();
+        std::fs::write(workdir.path().join(".gitignore"), "target").unwrap();
+        scaffold(workdir.path()).unwrap();
+        scaffold(workdir.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(workdir.path().join("Dockerfile")).unwrap(),
+            "FROM scratch\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(workdir.path().join(".gitignore")).unwrap(),
+            "target\n.env\n"
+        );
+
+        let empty = TempDir::new().unwrap();
+        scaffold(empty.path()).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(empty.path().join("Dockerfile")).unwrap(),
+            DOCKERFILE_STUB
+        );
+        assert_eq!(
+            std::fs::read_to_string(empty.path().join(".gitignore")).unwrap(),
+            ".env\n"
+        );
+    }
+
+    #[test]
+    fn pruned_tags_are_unpinned_by_cid() {
+        let pruned_tags = vec![String::from("app:QmOld"), String::from("app:QmOlder")];
+        assert_eq!(pruned_cids("app", &pruned_tags), vec!["QmOld", "QmOlder"]);
+    }
+
+    #[test]
+    fn dry_run_only_lists_what_it_would_do() {
+        let runtime = Rc::new(MockRuntime::default());
+        let plan = ContainerPlan {
+            runtime: runtime.clone(),
+            container_name: String::from("app"),
+            replace: false,
+            max_concurrent_docker_ops: 1,
+            health_check_timeout: Duration::from_secs(1),
+            host_config: HostConfig {
+                publish_all_ports: None,
+                port_bindings: None,
+                binds: Vec::new(),
+                restart_policy: config::RestartPolicy::default().into(),
+                memory: None,
+                nano_cpus: None,
+            },
+            env: Vec::new(),
+        };
+        assert!(!plan.dry_run(String::from("app:latest")).wait().unwrap());
+        assert_eq!(*runtime.calls.borrow(), vec!["list_images", "list"]);
+    }
+
+    #[test]
+    fn container_changes_are_sent_as_events() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let runtime = Rc::new(runtime::ObservedRuntime {
+            runtime: Rc::new(MockRuntime::default()),
+            events: tx,
+        });
+        remove_old_containers(
+            runtime.clone(),
+            String::from("new"),
+            String::from("app:latest"),
+            String::from("app"),
+            1,
+        )
+        .wait()
+        .unwrap();
+        runtime.start(String::from("new")).wait().unwrap();
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![
+                DeployEvent::Removing(String::from("old")),
+                DeployEvent::Starting(String::from("new")),
+            ]
+        );
+    }
+
+    #[test]
+    fn deploy_stage_durations_are_recorded_in_order() {
+        let timings = Rc::new(DeployTimings::new(log::Level::Debug));
+        let stage = |name: &'static str| {
+            let timings = timings.clone();
+            move |()| {
+                std::thread::sleep(Duration::from_millis(5));
+                timings.finish(name);
+                Ok::<(), Error>(())
+            }
+        };
+        future::ok::<(), Error>(())
+            .and_then(stage("clone"))
+            .and_then(stage("build"))
+            .and_then(stage("tag"))
+            .and_then(stage("start"))
+            .wait()
+            .unwrap();
+        let stages = timings.stages();
+        assert_eq!(
+            stages.iter().map(|(stage, _)| *stage).collect::<Vec<_>>(),
+            vec!["clone", "build", "tag", "start"]
+        );
+        assert!(stages
+            .iter()
+            .all(|(_, took)| *took >= Duration::from_millis(5)));
+        let finished = timings.finished.borrow();
+        assert!(finished.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+        assert_eq!(
+            timings.total(),
+            stages.iter().fold(Duration::default(), |total, (_, took)| total + *took)
+        );
+    }
+
+    /// Sets its flag when dropped, i.e. when the future holding it is abandoned.
+    struct DropFlag(Rc<std::cell::Cell<bool>>);
+
+    impl Drop for DropFlag {
+        fn drop(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn newer_deploy_aborts_the_one_in_progress() {
+        let mut slot = DeploySlot::default();
+        let stale_dropped = Rc::new(std::cell::Cell::new(false));
+        let drop_flag = DropFlag(stale_dropped.clone());
+        // Stands in for a build that is still running
+        let stale: BoxFuture<&str> = Box::new(future::empty::<(), Error>().map(move |()| {
+            let _drop_flag = &drop_flag;
+            "stale"
+        }));
+        let stale = slot.start(stale);
+        let newer = slot.start(Box::new(future::ok("newer")));
+        assert_eq!(stale.wait().unwrap(), None);
+        assert!(stale_dropped.get());
+        assert_eq!(newer.wait().unwrap(), Some("newer"));
+    }
+
+    #[test]
+    fn deploy_runs_to_completion_without_a_newer_one() {
+        let mut slot = DeploySlot::default();
+        let deploy = slot.start(Box::new(future::ok(true)));
+        drop(slot);
+        assert_eq!(deploy.wait().unwrap(), Some(true));
+    }
+
+    #[test]
+    fn incremental_push_only_sends_changed_files() {
+        let paths = |paths: &[&str]| -> Vec<PathBuf> { paths.iter().map(PathBuf::from).collect() };
+        let unchanged = "objects/aa/1111111111111111111111111111111111111111";
+        let gone = "objects/bb/2222222222222222222222222222222222222222";
+        let new = "objects/cc/3333333333333333333333333333333333333333";
+        let previous = paths(&["HEAD", "info/refs", unchanged, gone]);
+        let current = paths(&["HEAD", "info/refs", unchanged, new]);
+        let (send, removed) = changed_files(&previous, &current);
+        assert_eq!(send, paths(&["HEAD", "info/refs", new]));
+        assert_eq!(removed, paths(&[gone]));
+        // Neither sent nor unlinked, so the patched root keeps linking the pushed copy
+        assert!(!send.contains(&PathBuf::from(unchanged)));
+        assert!(!removed.contains(&PathBuf::from(unchanged)));
+    }
+
+    #[test]
+    fn only_loose_objects_count_as_unchanged() {
+        assert!(is_loose_object(Path::new(
+            "objects/aa/1111111111111111111111111111111111111111"
+        )));
+        assert!(!is_loose_object(Path::new("objects/pack/pack-1111.pack")));
+        assert!(!is_loose_object(Path::new("objects/info/packs")));
+        assert!(!is_loose_object(Path::new("refs/heads/master")));
+    }
+
+    #[test]
+    fn remote_pin_only_when_asked_for() {
+        let remote_pin = || {
+            Some(ipfs::RemotePinConfig {
+                endpoint: String::from("https://pinning.example.com"),
+                access_token_env_var: String::from("PIN_TOKEN"),
+            })
+        };
+        let default = PushOptions::default();
+        assert!(remote_pin_target(&default, remote_pin()).unwrap().is_none());
+        let pin_remote = PushOptions {
+            pin_remote: true,
+            ..PushOptions::default()
+        };
+        assert!(remote_pin_target(&pin_remote, remote_pin()).unwrap().is_some());
+        match remote_pin_target(&pin_remote, None) {
+            Err(box ErrorKind::RemotePinNotConfigured) => {}
+            _ => panic!("expected RemotePinNotConfigured"),
+        }
+    }
+
+    #[test]
+    fn temp_dir_is_created_in_configured_root() {
+        let root = TempDir::new().unwrap();
+        let tmp_dir = create_temp_dir(Some(root.path())).unwrap();
+        assert!(tmp_dir.path().starts_with(root.path()));
+    }
+
+    #[test]
+    fn temp_dir_creation_failure_is_typed() {
+        let root = Path::new("/nonexistent/lrad/tmp");
+        match create_temp_dir(Some(root)) {
+            Err(box ErrorKind::TempDirCreationFailed(path, _)) => assert_eq!(path, root),
+            _ => panic!("expected a TempDirCreationFailed error"),
+        }
+    }
+
+    #[test]
+    fn poll_intervals_vary_within_jitter_bounds() {
+        let base = Duration::from_secs(300);
+        let mut rng = rand::thread_rng();
+        let intervals: Vec<Duration> = (0..100).map(|_| jittered(base, 10, &mut rng)).collect();
+        assert!(intervals
+            .iter()
+            .all(|interval| *interval >= Duration::from_secs(270)
+                && *interval <= Duration::from_secs(330)));
+        assert!(intervals.iter().any(|interval| *interval != intervals[0]));
+        assert_eq!(jittered(base, 0, &mut rng), base);
+    }
+
+    #[test]
+    fn custom_config_path_is_loaded() {
+        let repo_dir = TempDir::new().unwrap();
+        Repository::init(repo_dir.path()).unwrap();
+        let config_dir = TempDir::new().unwrap();
+        let config_path = config_dir.path().join("staging.toml");
+        std::fs::write(
+            &config_path,
+            r#"
+            [dns_provider]
+            email_env_var = "CF_EMAIL"
+            api_key_env_var = "CF_API_KEY"
+            zone_id_env_var = "CF_ZONE_ID"
+            dns_record_id_env_var = "CF_DNS_RECORD_ID"
+            dns_record_name = "_dnslink.staging.example.com"
+
+            [ipfs_api_server]
+            host = "localhost"
+            port = 5001
+            "#,
+        )
+        .unwrap();
+        assert!(LradCli::try_load(repo_dir.path(), None).is_err());
+        let lrad = LradCli::try_load(repo_dir.path(), Some(&config_path)).unwrap();
+        assert_eq!(
+            lrad.config.dns_provider.dns_record_name(),
+            "_dnslink.staging.example.com"
+        );
+
+        let daemon_config_path = config_dir.path().join("lrad-daemon.toml");
+        config::DaemonConfig::template()
+            .write(&daemon_config_path)
+            .unwrap();
+        let daemon = LradDaemon::try_load(Some(&daemon_config_path)).unwrap();
+        assert_eq!(daemon.targets()[0].dns_record_name, "_dnslink.example.com");
+    }
+
+    #[test]
+    fn daemon_template_config_parses() {
+        let tmp_dir = TempDir::new().unwrap();
+        let config_path = tmp_dir.path().join("lrad-daemon.toml");
+        config::DaemonConfig::template().write(&config_path).unwrap();
+        let config = config::DaemonConfig::try_from(&config_path).unwrap();
+        assert_eq!(config.targets[0].dns_record_name, "_dnslink.example.com");
+    }
+
+    #[test]
+    fn daemon_systemd_unit_has_exec_and_restart() {
+        let unit = LradDaemon::systemd_unit(Path::new("/usr/bin/lrad-daemon"), None);
+        assert!(unit.contains("ExecStart=/usr/bin/lrad-daemon\n"));
+        assert!(unit.contains("Restart=on-failure\n"));
+
+        let unit = LradDaemon::systemd_unit(
+            Path::new("/usr/bin/lrad-daemon"),
+            Some(Path::new("/opt/lrad.toml")),
+        );
+        assert!(unit.contains("ExecStart=/usr/bin/lrad-daemon -c /opt/lrad.toml\n"));
+    }
+}
+
+/// Creates a temporary directory under `root`, or the system default if there is none.
+fn create_temp_dir(root: Option<&Path>) -> Result<TempDir> {
+    let root = root
+        .map(PathBuf::from)
+        .unwrap_or_else(std::env::temp_dir);
+    tempfile::Builder::new()
+        .prefix("lrad")
+        .tempdir_in(&root)
+        .map_err(|err| ErrorKind::TempDirCreationFailed(root, err).into())
+}
+
+/// Clones the repo published under the IPNS name, i.e. a DNS record name or the name of a node
+/// key. It is fetched through the IPFS API, or with `git clone` through the IPFS gateway at
+/// `gateway_url` if that fails. With `trusted_signers`, the clone is rejected unless its HEAD
+/// commit is signed by one of them.
+fn clone_deployment(
+    ipfs_api_server: &ipfs::IpfsApiServerConfig,
+    gateway_url: &str,
+    ipns_name: &str,
+    temp_dir: Option<&Path>,
+    trusted_signers: Option<&config::TrustedSigners>,
+) -> Result<(TempDir, PathBuf)> {
+    let (tmp_dir, repo) = match fetch_deployment(ipfs_api_server, ipns_name, temp_dir) {
+        Ok(fetched) => fetched,
+        Err(err) => {
+            warn!(
+                "Failed to fetch /ipns/{} through the IPFS API, trying git clone {:?}",
+                ipns_name, err
+            );
+            git_clone_deployment(gateway_url, ipns_name, temp_dir)?
+        }
+    };
+    if let Some(trusted_signers) = trusted_signers {
+        vcs::verify_head_signature(&repo, trusted_signers)?;
+    }
+    match repo.workdir() {
+        Some(workdir) => Ok((tmp_dir, workdir.to_path_buf())),
+        None => Err(vcs::VcsError::RepoShouldNotBeBare.into()),
+    }
+}
+
+/// Downloads the published repo from the IPFS node and checks it out with git2, needing neither
+/// the git binary nor a gateway.
+fn fetch_deployment(
+    ipfs_api_server: &ipfs::IpfsApiServerConfig,
+    ipns_name: &str,
+    temp_dir: Option<&Path>,
+) -> Result<(TempDir, Repository)> {
+    let tmp_dir = create_temp_dir(temp_dir)?;
+    debug!("Fetching git repo published under /ipns/{}", ipns_name);
+    let published = fetch_published(ipfs_api_server, &format!("/ipns/{}", ipns_name), &tmp_dir)?;
+    let repo = vcs::checkout(&published, &tmp_dir.path().join("checkout"))?;
+    Ok((tmp_dir, repo))
+}
+
+/// Downloads what the IPFS `path` points at into `tmp_dir`, returning where it ended up.
+fn fetch_published(
+    ipfs_api_server: &ipfs::IpfsApiServerConfig,
+    path: &str,
+    tmp_dir: &TempDir,
+) -> Result<PathBuf> {
+    let published_dir = tmp_dir.path().join("published");
+    ipfs::get(ipfs_api_server, path, &published_dir)?;
+    match std::fs::read_dir(&published_dir)?.next() {
+        Some(entry) => Ok(entry?.path()),
+        None => {
+            let message = format!("{} fetched nothing", path);
+            Err(std::io::Error::new(std::io::ErrorKind::NotFound, message).into())
+        }
+    }
+}
+
+/// The tree of the HEAD commit of the repo at `path`.
+fn head_tree(path: &Path) -> Result<git2::Oid> {
+    let repo = Repository::open(path)?;
+    let tree_id = repo.head()?.peel_to_commit()?.tree_id();
+    Ok(tree_id)
+}
+
+/// Clones the published repo through the gateway with the git binary, which speaks the dumb HTTP
+/// protocol that libgit2 doesn't.
+fn git_clone_deployment(
+    gateway_url: &str,
+    ipns_name: &str,
+    temp_dir: Option<&Path>,
+) -> Result<(TempDir, Repository)> {
+    let tmp_dir = create_temp_dir(temp_dir)?;
+    debug!("Cloning git repo published under /ipns/{}", ipns_name);
+    let url = format!("{}/ipns/{}", gateway_url.trim_end_matches('/'), ipns_name);
+    vcs::run_git(tmp_dir.path(), &["clone", &url, "--single-branch"])?;
+    let mut repo_path = tmp_dir.path().to_path_buf();
+    repo_path.push(ipns_name);
+    let repo = Repository::discover(repo_path)?;
+    Ok((tmp_dir, repo))
+}
+
+fn send_event(events: &Option<mpsc::Sender<DeployEvent>>, event: DeployEvent) {
+    if let Some(events) = events {
+        // Nobody listening anymore is no reason to stop deploying
+        let _ = events.send(event);
+    }
+}
+
+/// File in a published repo naming the prebuilt image to pull for it.
+const IMAGE_REF_FILE: &str = ".lrad-image";
+
+fn read_image_ref(workdir: &Path) -> Result<String> {
+    let image_ref = std::fs::read_to_string(workdir.join(IMAGE_REF_FILE))?;
+    Ok(String::from(image_ref.trim()))
+}
+
+#[derive(Default)]
+pub struct InitOptions {
+    /// Also write a starter Dockerfile and add .env to .gitignore, leaving existing ones be
+    pub scaffold: bool,
+}
+
+/// What `lrad init --scaffold` starts the Dockerfile out as, which the daemon builds from the
+/// root of the repo.
+const DOCKERFILE_STUB: &str = r#"# lrad-daemon builds this on the device, with the root of the repo as the build context.
+# Pick a base image for the device's architecture, e.g. arm32v7/ or arm64v8/ ones.
+FROM alpine:3.8
+
+WORKDIR /app
+COPY . .
+
+# Replace with what starts your app
+CMD ["sh", "-c", "echo 'Hello from lrad!' && sleep 3600"]
+"#;
+
+/// Writes a starter Dockerfile into `workdir` unless it has one already, and makes sure .env is
+/// ignored by git.
+fn scaffold(workdir: &Path) -> Result<()> {
+    let dockerfile = workdir.join("Dockerfile");
+    match std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&dockerfile)
+    {
+        Ok(mut file) => {
+            file.write_all(DOCKERFILE_STUB.as_bytes())?;
+            info!("Wrote a starter {}", dockerfile.display());
+        }
+        Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            info!("Keeping the existing {}", dockerfile.display());
+        }
+        Err(err) => return Err(err.into()),
+    }
+    if append_to_gitignore(&workdir.join(".gitignore"), ".env")? {
+        info!("Added .env to .gitignore");
+    }
+    Ok(())
+}
+
+/// Appends `entry` to the .gitignore at `path` unless it is already listed there, returning
+/// whether it was added.
+fn append_to_gitignore(path: &Path, entry: &str) -> Result<bool> {
+    let existing = match std::fs::read_to_string(path) {
+        Ok(existing) => existing,
+        Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let rooted = format!("/{}", entry);
+    if existing
+        .lines()
+        .any(|line| line.trim() == entry || line.trim() == rooted)
+    {
+        return Ok(false);
+    }
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+    if !existing.is_empty() && !existing.ends_with('\n') {
+        file.write_all(b"\n")?;
+    }
+    writeln!(file, "{}", entry)?;
+    Ok(true)
+}
+
+#[derive(Default)]
+pub struct PushOptions {
+    /// Push even if HEAD has files matched by .gitignore
+    pub include_ignored: bool,
+    /// Also pin the pushed content on the configured remote pinning service
+    pub pin_remote: bool,
+    /// Publish to the IPNS name of this IPFS node key instead of updating the DNS record
+    pub ipns_key: Option<String>,
+    /// Only add the files that changed since the last push, and link them into its root
+    pub incremental: bool,
+}
+
+/// Where the last push is recorded, inside .git so that it is never committed.
+const PUSH_RECORD_FILE: &str = "lrad-push.toml";
+
+/// What the last push added, for the next push to only add what changed since.
+#[derive(Deserialize, Serialize)]
+struct PushRecord {
+    commit: String,
+    root: String,
+    /// Every file under the root, relative to it
+    files: Vec<PathBuf>,
+}
+
+impl PushRecord {
+    fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(toml::from_str(&std::fs::read_to_string(path)?)?))
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        std::fs::write(path, toml::to_string(self)?)?;
+        Ok(())
+    }
+}
+
+/// Whether `path` is a loose object, i.e. objects/xx/yyyy... named after its content.
+fn is_loose_object(path: &Path) -> bool {
+    let is_hex = |part: &str| part.chars().all(|c| c.is_ascii_hexdigit());
+    let parts: Vec<&str> = path
+        .components()
+        .filter_map(|component| component.as_os_str().to_str())
+        .collect();
+    match parts.as_slice() {
+        ["objects", dir, name] => dir.len() == 2 && is_hex(dir) && is_hex(name),
+        _ => false,
+    }
+}
+
+/// The files to add and the files of the last push to unlink from its root. A loose object that
+/// was pushed before can't have changed, every other file is added again.
+fn changed_files(previous: &[PathBuf], current: &[PathBuf]) -> (Vec<PathBuf>, Vec<PathBuf>) {
+    let previous_files: HashSet<&PathBuf> = previous.iter().collect();
+    let current_files: HashSet<&PathBuf> = current.iter().collect();
+    let send = current
+        .iter()
+        .filter(|file| !(is_loose_object(file) && previous_files.contains(file)))
+        .cloned()
+        .collect();
+    let removed = previous
+        .iter()
+        .filter(|file| !current_files.contains(file))
+        .cloned()
+        .collect();
+    (send, removed)
+}
+
+/// Explodes the packs of a fresh bare clone into loose objects, which keep their paths from one
+/// push to the next where a pack would be rewritten as a whole.
+fn unpack_objects(bare_repo_path: &Path) -> Result<()> {
+    let pack_dir = bare_repo_path.join("objects").join("pack");
+    if !pack_dir.is_dir() {
+        return Ok(());
+    }
+    let packs = pack_dir
+        .read_dir()?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    for pack in packs
+        .into_iter()
+        .filter(|path| path.extension() == Some(OsStr::new("pack")))
+    {
+        // Objects still in a pack would be skipped by unpack-objects
+        let pack_bytes = std::fs::read(&pack)?;
+        std::fs::remove_file(pack.with_extension("idx"))?;
+        std::fs::remove_file(&pack)?;
+        let mut unpack = Command::new("git")
+            .arg("unpack-objects")
+            .arg("-q")
+            .current_dir(bare_repo_path)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        unpack.stdin.take().unwrap().write_all(&pack_bytes)?;
+        let output = unpack.wait_with_output()?;
+        if !output.status.success() {
+            let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+            return Err(
+                ErrorKind::GitCommandFailed(String::from("unpack-objects -q"), stderr).into(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The remote pinning service to pin to, which has to be configured if it was asked for.
+fn remote_pin_target(
+    options: &PushOptions,
+    remote_pin: Option<ipfs::RemotePinConfig>,
+) -> Result<Option<ipfs::RemotePinConfig>> {
+    match remote_pin {
+        _ if !options.pin_remote => Ok(None),
+        Some(remote_pin) => Ok(Some(remote_pin)),
+        None => Err(ErrorKind::RemotePinNotConfigured.into()),
+    }
 }
 
 pub struct LradCli {
@@ -41,32 +996,104 @@ pub struct LradCli {
 }
 
 impl LradCli {
-    pub fn try_load(path: &Path) -> Result<Self> {
+    /// Loads the repo containing `path` along with its config, `.lrad.toml` at the root of the
+    /// repo unless `config_path` names another one. A relative `config_path` is taken from the
+    /// root of the repo and has to stay inside it.
+    pub fn try_load(path: &Path, config_path: Option<&Path>) -> Result<Self> {
         let repo = Repository::discover(path)?;
-        let config = config::CliConfig::try_from(&repo)?;
+        let config = config::CliConfig::try_from(&repo, config_path)?;
         Ok(LradCli { repo, config })
     }
 
-    pub fn try_init(path: &Path) -> Result<Self> {
+    /// The loaded configuration as TOML, with credentials redacted.
+    pub fn show_config(&self) -> Result<String> {
+        config::render_redacted(&self.config)
+    }
+
+    /// Writes a config to fill in for the repo containing `path`, where `try_load` with the same
+    /// `config_path` finds it.
+    pub fn try_init(path: &Path, config_path: Option<&Path>, options: InitOptions) -> Result<Self> {
         debug!("Finding repo...");
         let repo = Repository::discover(path)?;
         debug!("Found repo at {:#?}", repo.path());
         let config = config::CliConfig::default();
-        config.write(&repo)?;
+        config.write(&repo, config_path)?;
+        if options.scaffold {
+            match repo.workdir() {
+                Some(workdir) => scaffold(workdir)?,
+                None => return Err(vcs::VcsError::RepoShouldNotBeBare.into()),
+            }
+        }
         if !repo.status_should_ignore(Path::new(".env"))? {
             warn!("The .env file may accidentally be committed! Please add it to your .gitignore if you plan on using it to store secrets.");
         }
         Ok(LradCli { repo, config })
     }
 
-    pub fn try_push(self) -> BoxFuture<String> {
-        if self.repo.state() != RepositoryState::Clean {
-            return Box::new(future::err(vcs::VcsError::RepoNotClean.into()));
+    /// Fetches what the DNS link record points to from IPFS and compares its HEAD tree to the one
+    /// of the local repo, to tell whether what is live is what was pushed.
+    pub fn try_verify(&self) -> BoxFuture<Verification> {
+        let local_tree = match head_tree(self.repo.path()) {
+            Ok(local_tree) => local_tree,
+            Err(err) => return Box::new(future::err(err)),
+        };
+        let ipfs_api_server = self.config.ipfs_api_server.clone();
+        let temp_dir = self.config.temp_dir.clone();
+        Box::new(
+            DnsTxtRecordResponse::lookup_txt_record(self.config.dns_provider.dns_record_name())
+                .and_then(move |record| -> Result<_> {
+                    let cid = match record.as_ref().and_then(DnsTxtRecordResponse::as_hash) {
+                        Some(cid) => cid,
+                        None => return Ok(Verification::NotPublished),
+                    };
+                    let tmp_dir = create_temp_dir(temp_dir.as_ref().map(PathBuf::as_path))?;
+                    debug!("Fetching /ipfs/{}", cid);
+                    let published =
+                        fetch_published(&ipfs_api_server, &format!("/ipfs/{}", cid), &tmp_dir)?;
+                    let published_tree = head_tree(&published)?;
+                    Ok(verification(cid, published_tree, local_tree))
+                }),
+        )
+    }
+
+    pub fn try_push(self, options: PushOptions) -> BoxFuture<String> {
+        if let Err(err) = vcs::check_state(&self.repo) {
+            return Box::new(future::err(err.into()));
         } else if self.repo.is_bare() {
             return Box::new(future::err(vcs::VcsError::RepoShouldNotBeBare.into()));
         }
+        if !options.include_ignored {
+            match vcs::committed_ignored_files(&self.repo) {
+                Ok(ref ignored) if ignored.is_empty() => {}
+                Ok(ignored) => {
+                    for path in ignored {
+                        warn!("{} is committed, but matched by .gitignore", path.display());
+                    }
+                    return Box::new(future::err(vcs::VcsError::HeadHasIgnoredFiles.into()));
+                }
+                Err(err) => return Box::new(future::err(err)),
+            }
+        }
+        debug!("Checking that the IPFS daemon is reachable...");
+        if let Err(err) = ipfs::check_daemon(&self.config.ipfs_api_server) {
+            return Box::new(future::err(err));
+        }
         let repo = Rc::new(self.repo);
+        let temp_dir = self.config.temp_dir;
+        let remote_pin = match remote_pin_target(&options, self.config.remote_pin) {
+            Ok(remote_pin) => remote_pin,
+            Err(err) => return Box::new(future::err(err)),
+        };
         let ipfs_api_server = Rc::new(self.config.ipfs_api_server);
+        let publish_api_server = ipfs_api_server.clone();
+        let incremental = options.incremental;
+        let ipns_key = options.ipns_key;
+        let commit = match repo.head().and_then(|head| head.peel_to_commit()) {
+            Ok(commit) => commit.id().to_string(),
+            Err(err) => return Box::new(future::err(err.into())),
+        };
+        let push_record_path = repo.path().join(PUSH_RECORD_FILE);
+        let previous_push_path = push_record_path.clone();
         let dns_provider = Rc::new(self.config.dns_provider);
         Box::new(
             future::result(repo.index().map_err(|err| err.into()))
@@ -88,10 +1115,10 @@ impl LradCli {
                     let repo_path = PathBuf::from(repo.path());
                     Ok(repo_path)
                 })
-                .and_then(|repo_path| {
+                .and_then(move |repo_path| {
                     info!("Converting to bare repo...");
                     let repo_path = repo_path.parent().unwrap();
-                    let tmp_dir = TempDir::new()?;
+                    let tmp_dir = create_temp_dir(temp_dir.as_ref().map(PathBuf::as_path))?;
                     let mut bare_repo_path = PathBuf::from(tmp_dir.path());
                     bare_repo_path.push(repo_path.file_name().unwrap());
                     let bare_repo = vcs::clone_bare(repo_path.to_str().unwrap(), &bare_repo_path)?;
@@ -101,23 +1128,98 @@ impl LradCli {
                             bare_repo.remote_delete(&remote.unwrap())?;
                         }
                     }
+                    if incremental {
+                        debug!("Unpacking objects");
+                        unpack_objects(&bare_repo_path)?;
+                    }
                     debug!("Updating server info");
-                    Command::new("git")
-                        .arg("update-server-info")
-                        .current_dir(&bare_repo_path)
-                        .output()?;
+                    vcs::update_server_info(&bare_repo)?;
                     Ok((tmp_dir, bare_repo_path))
                 })
-                .and_then(move |(_tmp_dir, bare_repo_path)| {
-                    info!("Adding files to IPFS...");
-                    ipfs::IpfsAddRecursive::new(&ipfs_api_server, &bare_repo_path).run()
+                .and_then(move |(_tmp_dir, bare_repo_path)| -> Result<_> {
+                    let add = ipfs::IpfsAddRecursive::new(&ipfs_api_server, &bare_repo_path);
+                    let files = add.files()?;
+                    let previous = if incremental {
+                        PushRecord::read(&previous_push_path)?
+                    } else {
+                        None
+                    };
+                    let root_name = bare_repo_path
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .into_owned();
+                    let root = match previous {
+                        Some(previous) => {
+                            let (send, removed) = changed_files(&previous.files, &files);
+                            info!(
+                                "Adding {} of {} files to IPFS, the rest is unchanged since {}...",
+                                send.len(),
+                                files.len(),
+                                previous.commit
+                            );
+                            let sent: HashSet<PathBuf> = send.iter().cloned().collect();
+                            let added = add.only(send).run()?;
+                            let mut root = previous.root;
+                            for path in &removed {
+                                root = ipfs::patch_rm_link(&ipfs_api_server, &root, path)?;
+                            }
+                            for added in added {
+                                let path = Path::new(&added.name).strip_prefix(&root_name);
+                                match path {
+                                    Ok(path) if sent.contains(path) => {
+                                        root = ipfs::patch_add_link(
+                                            &ipfs_api_server,
+                                            &root,
+                                            path,
+                                            &added.hash,
+                                        )?;
+                                    }
+                                    _ => {}
+                                }
+                            }
+                            ipfs::pin_add(&ipfs_api_server, &root)?;
+                            root
+                        }
+                        None => {
+                            info!("Adding files to IPFS...");
+                            match add.run()?.pop() {
+                                Some(root) => root.hash,
+                                None => {
+                                    return Err(ErrorKind::NothingAddedToIpfs(
+                                        bare_repo_path.clone(),
+                                    )
+                                    .into())
+                                }
+                            }
+                        }
+                    };
+                    Ok((root_name, root, files))
                 })
-                .and_then(move |ipfs_add_response| {
-                    info!("Updating Cloudflare DNS Record...");
-                    let root = ipfs_add_response.iter().last().unwrap();
-                    dns_provider.try_put_txt_record(root.hash.clone()).wait()?;
+                .and_then(move |(root_name, root, files)| {
+                    if let Some(remote_pin) = &remote_pin {
+                        info!("Pinning on remote pinning service...");
+                        ipfs::pin_remote(remote_pin, &root, &root_name)?;
+                    }
+                    match &ipns_key {
+                        Some(ipns_key) => {
+                            info!("Publishing to IPNS...");
+                            let name = ipfs::publish_name(&publish_api_server, ipns_key, &root)?;
+                            info!("Published to /ipns/{}", name);
+                        }
+                        None => {
+                            info!("Updating Cloudflare DNS Record...");
+                            dns_provider.try_put_txt_record(root.clone()).wait()?;
+                        }
+                    }
+                    PushRecord {
+                        commit,
+                        root: root.clone(),
+                        files,
+                    }
+                    .write(&push_record_path)?;
 
-                    Ok(root.hash.clone())
+                    Ok(root)
                 }),
         )
     }
@@ -128,15 +1230,185 @@ pub struct LradDaemon {
 }
 
 impl LradDaemon {
-    pub fn try_load(path: &Path) -> Result<Self> {
-        let config = config::DaemonConfig::try_from(path)?;
+    /// Loads the config at `config_path`, or from stdin if it is "-". Without one, the config is
+    /// taken from `DAEMON_CONFIG_ENV_VAR` if set, or else from `DAEMON_CONFIG_PATH`.
+    pub fn try_load(config_path: Option<&Path>) -> Result<Self> {
+        let config = match config_path {
+            Some(config_path) if config_path == Path::new("-") => {
+                debug!("Loading configuration from stdin");
+                let mut s = String::new();
+                std::io::Read::read_to_string(&mut std::io::stdin(), &mut s)?;
+                config::DaemonConfig::try_from_str(&s)?
+            }
+            None if std::env::var_os(config::DAEMON_CONFIG_ENV_VAR).is_some() => {
+                debug!("Loading configuration from {}", config::DAEMON_CONFIG_ENV_VAR);
+                config::DaemonConfig::from_env(config::DAEMON_CONFIG_ENV_VAR)?
+            }
+            _ => {
+                let config_path =
+                    config_path.unwrap_or_else(|| Path::new(config::DAEMON_CONFIG_PATH));
+                debug!("Loading configuration from {}", config_path.display());
+                config::DaemonConfig::try_from(config_path)?
+            }
+        };
         Ok(LradDaemon { config })
     }
 
+    /// The loaded configuration as TOML, with credentials redacted.
+    pub fn show_config(&self) -> Result<String> {
+        config::render_redacted(&self.config)
+    }
+
+    /// The deployments this daemon keeps up to date, each looked up and deployed on its own.
+    pub fn targets(&self) -> &[config::DeployTarget] {
+        &self.config.targets
+    }
+
+    /// The target with `dns_record_name`, or the first one if none is asked for.
+    pub fn target(&self, dns_record_name: Option<&str>) -> Result<&config::DeployTarget> {
+        match dns_record_name {
+            Some(dns_record_name) => self
+                .config
+                .targets
+                .iter()
+                .find(|target| target.dns_record_name == dns_record_name)
+                .ok_or_else(|| {
+                    ErrorKind::UnknownDeployTarget(String::from(dns_record_name)).into()
+                }),
+            None => Ok(&self.config.targets[0]),
+        }
+    }
+
+    /// Reports the outcome of deploying `cid` to `target` to the configured `notify_url`.
+    pub fn deploy_notifier(
+        &self,
+        target: &config::DeployTarget,
+        cid: Option<&str>,
+    ) -> notify::DeployNotifier {
+        notify::DeployNotifier {
+            url: self.config.notify_url.clone(),
+            device_id: self
+                .config
+                .device_id
+                .clone()
+                .unwrap_or_else(notify::hostname),
+            dns_record: target.dns_record_name.clone(),
+            cid: cid.map(String::from),
+        }
+    }
+
+    /// How long to wait before the next DNS lookup.
+    pub fn next_poll_interval(&self) -> Duration {
+        jittered(
+            self.config
+                .poll_interval_secs
+                .map(|secs| Duration::from_secs(secs.max(1)))
+                .unwrap_or(DEFAULT_POLL_INTERVAL),
+            self.config.poll_jitter_percent.unwrap_or(0),
+            &mut rand::thread_rng(),
+        )
+    }
+
+    pub fn status_addr(&self) -> Option<SocketAddr> {
+        self.config.status_addr
+    }
+
+    pub fn integrity_check_interval(&self) -> Option<Duration> {
+        self.config
+            .integrity_check_interval_secs
+            .map(Duration::from_secs)
+    }
+
+    /// Re-verifies the deployed content in the local IPFS store, returning whether it had to be
+    /// re-fetched.
+    pub fn try_check_integrity(&self, record: &DnsTxtRecordResponse) -> Result<bool> {
+        match record.as_hash() {
+            Some(cid) => ipfs::verify_or_refetch(&self.config.ipfs_api_server, &cid),
+            None => Ok(false),
+        }
+    }
+
+    /// Writes a template daemon config and a systemd unit that runs the daemon with it.
+    /// Existing files are left untouched.
+    pub fn try_init(config_path: &Path, unit_path: &Path, binary_path: &Path) -> Result<()> {
+        if config_path.exists() {
+            warn!(
+                "{} already exists, not overwriting it.",
+                config_path.display()
+            );
+        } else {
+            if let Some(parent) = config_path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            debug!("Writing daemon config to {}", config_path.display());
+            config::DaemonConfig::template().write(config_path)?;
+        }
+        if unit_path.exists() {
+            warn!("{} already exists, not overwriting it.", unit_path.display());
+        } else {
+            let custom_config_path = if config_path != Path::new(config::DAEMON_CONFIG_PATH) {
+                Some(config_path)
+            } else {
+                None
+            };
+            debug!("Writing systemd unit to {}", unit_path.display());
+            std::fs::write(
+                unit_path,
+                Self::systemd_unit(binary_path, custom_config_path),
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn systemd_unit(binary_path: &Path, config_path: Option<&Path>) -> String {
+        let exec_start = match config_path {
+            Some(config_path) => format!("{} -c {}", binary_path.display(), config_path.display()),
+            None => format!("{}", binary_path.display()),
+        };
+        format!(
+            "[Unit]
+Description=lrad Daemon
+After=network.target
+
+[Service]
+User=root
+ExecStart={}
+Restart=on-failure
+
+[Install]
+WantedBy=default.target
+Alias=lrad-daemon.service
+",
+            exec_start
+        )
+    }
+
+    /// Looks up the record to deploy, retrying failures that may clear up within this poll.
     pub fn try_lookup_txt_record(
         &self,
+        target: &config::DeployTarget,
     ) -> impl Future<Item = Option<DnsTxtRecordResponse>, Error = Error> {
-        DnsTxtRecordResponse::lookup_txt_record(&self.config.dns_record_name)
+        if let Some(ipns_name) = &target.ipns_name {
+            debug!("Resolving /ipns/{}", ipns_name);
+            return future::Either::A(future::result(
+                ipfs::resolve_name(&self.config.ipfs_api_server, ipns_name).map(|cid| {
+                    Some(DnsTxtRecordResponse {
+                        txt_data: vec![dns::DnsLink::Ipfs(cid).to_string()],
+                    })
+                }),
+            ));
+        }
+        future::Either::B(DnsTxtRecordResponse::lookup_txt_record_with_retry(
+            &target.dns_record_name,
+            self.config
+                .dns_lookup_attempts
+                .unwrap_or(DEFAULT_DNS_LOOKUP_ATTEMPTS)
+                .max(1),
+            self.config
+                .dns_retry_base_delay_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_DNS_RETRY_BASE_DELAY),
+        ))
         // .or_else(|err| {
         //     match &err {
         //         box ErrorKind::TrustDnsResolveError(resolve_err) => match resolve_err.kind() {
@@ -145,7 +1417,7 @@ impl LradDaemon {
         //                 valid_until: _,
         //             } => DnsTxtRecordResponse::lookup_txt_record(&format!(
         //                 "_dnslink.{}",
-        //                 &self.config.dns_record_name
+        //                 &target.dns_record_name
         //             )),
         //             _ => future::err(err),
         //         },
@@ -154,90 +1426,1155 @@ impl LradDaemon {
         // })
     }
 
-    pub fn try_deploy(&self) -> BoxFuture<bool> {
-        let dns_record_name = self.config.dns_record_name.get("_dnslink.".len()..);
+    /// Publishes the configured port map, mounts the configured volumes, and sets the restart
+    /// policy and resource limits. Port bindings are left unset if there are none.
+    fn host_config(target: &config::DeployTarget) -> HostConfig {
+        let port_bindings = if target.port_map.is_empty() {
+            None
+        } else {
+            let mut port_bindings = HashMap::with_capacity(target.port_map.len());
+            target
+                .port_map
+                .iter()
+                .map(|x| (x.0, x.1.iter().map(|y| y.into()).collect()))
+                .for_each(|x| {
+                    port_bindings.insert(x.0.clone(), x.1);
+                });
+            debug!("Port bindings are {:?}", port_bindings);
+            Some(port_bindings)
+        };
+        HostConfig {
+            port_bindings,
+            publish_all_ports: None,
+            binds: target.binds.clone(),
+            restart_policy: target.restart_policy.into(),
+            memory: target.memory_bytes,
+            nano_cpus: target.nano_cpus,
+        }
+    }
+
+    /// What deploys run through: the configured CLI if there is one, or else the Docker API with
+    /// requests retried as configured.
+    fn runtime(&self) -> Rc<ContainerRuntime> {
+        if let Some(program) = &self.config.runtime_command {
+            return Rc::new(CommandRuntime::new(program.clone()));
+        }
+        let default_retry = RetryPolicy::default();
+        Rc::new(Docker {
+            connection: self.config.docker.clone(),
+            retry: RetryPolicy {
+                attempts: self
+                    .config
+                    .docker_retry_attempts
+                    .unwrap_or(default_retry.attempts)
+                    .max(1),
+                base_delay: self
+                    .config
+                    .docker_retry_base_delay_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(default_retry.base_delay),
+            },
+            compress_context: self.compress_build_context(),
+        })
+    }
+
+    fn compress_build_context(&self) -> bool {
+        self.config
+            .compress_build_context
+            .unwrap_or_else(|| match self.config.docker {
+                docker::DockerConnection::Tcp(_) => true,
+                docker::DockerConnection::Unix(_) => false,
+            })
+    }
+
+    /// Collects what is needed to run a built image with `runtime`, so that it can move into a
+    /// deploy future.
+    fn container_plan(
+        &self,
+        target: &config::DeployTarget,
+        container_name: String,
+        runtime: Rc<ContainerRuntime>,
+    ) -> ContainerPlan {
+        ContainerPlan {
+            runtime,
+            container_name,
+            replace: target.replace,
+            max_concurrent_docker_ops: self
+                .config
+                .max_concurrent_docker_ops
+                .unwrap_or(DEFAULT_MAX_CONCURRENT_DOCKER_OPS)
+                .max(1),
+            health_check_timeout: target
+                .health_check_timeout_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_HEALTH_CHECK_TIMEOUT),
+            host_config: Self::host_config(target),
+            env: target.env.clone(),
+        }
+    }
+
+    /// Deploys the content published under the target's DNS record. If the `cid` it points to is
+    /// known, the image is also tagged with it, and only the most recent of those tags are kept.
+    pub fn try_deploy(&self, target: &config::DeployTarget, cid: Option<&str>) -> BoxFuture<bool> {
+        self.deploy(target, cid, false, None)
+    }
+
+    /// Deploys like `try_deploy`, also sending each step to `events` as it happens.
+    pub fn try_deploy_with_events(
+        &self,
+        target: &config::DeployTarget,
+        cid: Option<&str>,
+        events: mpsc::Sender<DeployEvent>,
+    ) -> BoxFuture<bool> {
+        self.deploy(target, cid, false, Some(events))
+    }
+
+    /// Clones and builds like `try_deploy`, but only logs the image it would run and the
+    /// containers it would remove, leaving containers and image tags alone.
+    pub fn try_deploy_dry_run(
+        &self,
+        target: &config::DeployTarget,
+        cid: Option<&str>,
+    ) -> BoxFuture<bool> {
+        self.deploy(target, cid, true, None)
+    }
+
+    fn deploy(
+        &self,
+        target: &config::DeployTarget,
+        cid: Option<&str>,
+        dry_run: bool,
+        events: Option<mpsc::Sender<DeployEvent>>,
+    ) -> BoxFuture<bool> {
+        let dns_record_name = target.dns_record_name.get("_dnslink.".len()..);
 
         if dns_record_name.is_none() {
             return Box::new(future::ok(false));
         }
         let dns_record_name = String::from(dns_record_name.unwrap());
-        let mut port_bindings = HashMap::with_capacity(self.config.port_map.len());
-        self.config
-            .port_map
-            .iter()
-            .map(|x| (x.0, x.1.iter().map(|y| y.into()).collect()))
-            .for_each(|x| {
-                port_bindings.insert(x.0.clone(), x.1);
-            });
-        debug!("Port bindings are {:?}", port_bindings);
+        let runtime: Rc<ContainerRuntime> = match events.clone() {
+            Some(events) => Rc::new(ObservedRuntime {
+                runtime: self.runtime(),
+                events,
+            }),
+            None => self.runtime(),
+        };
+        let plan = self.container_plan(target, dns_record_name.clone(), runtime.clone());
+        let cid = cid.map(String::from);
+        let deployment = dns_record_name.clone();
+        let keep_images = target
+            .keep_images
+            .unwrap_or(DEFAULT_KEEP_IMAGES)
+            .max(1);
+        let temp_dir = self.config.temp_dir.clone();
+        let trusted_signers = self.config.trusted_signers.clone();
+        let ipfs_api_server = self.config.ipfs_api_server.clone();
+        let pin = if self.config.pin_deployments {
+            Some((self.config.ipfs_api_server.clone(), cid.clone()))
+        } else {
+            None
+        };
+        let gateway_url = self
+            .config
+            .gateway_url
+            .clone()
+            .unwrap_or_else(|| String::from(DEFAULT_GATEWAY_URL));
+        let ipns_name = target
+            .ipns_name
+            .clone()
+            .unwrap_or_else(|| dns_record_name.clone());
+        let timings = Rc::new(DeployTimings::new(if self.config.log_stage_durations {
+            log::Level::Info
+        } else {
+            log::Level::Debug
+        }));
+        let image: BoxFuture<String> = match target.deploy_mode.clone() {
+            config::DeployMode::BuildFromRepo => {
+                let runtime = runtime.clone();
+                let timings = timings.clone();
+                Box::new(
+                    future::lazy({
+                        let timings = timings.clone();
+                        move || -> Result<_> {
+                            let image_name = format!("{}:latest", dns_record_name);
+                            send_event(&events, DeployEvent::Cloning(ipns_name.clone()));
+                            let cloned = clone_deployment(
+                                &ipfs_api_server,
+                                &gateway_url,
+                                &ipns_name,
+                                temp_dir.as_ref().map(PathBuf::as_path),
+                                trusted_signers.as_ref(),
+                            )?;
+                            timings.finish("clone");
+                            Ok((cloned, image_name))
+                        }
+                    })
+                    .and_then(move |((tmp_dir, workdir), image_name)| {
+                        runtime
+                            .build(workdir, image_name.clone())
+                            .map(|_ok| (image_name, tmp_dir))
+                    })
+                    .map(move |(image_name, _tmp_dir)| {
+                        timings.finish("build");
+                        image_name
+                    }),
+                )
+            }
+            config::DeployMode::PullImage { image_ref } => {
+                let runtime = runtime.clone();
+                let timings = timings.clone();
+                Box::new(
+                    future::lazy({
+                        let timings = timings.clone();
+                        move || -> Result<String> {
+                            match image_ref {
+                                Some(image_ref) => Ok(image_ref),
+                                None => {
+                                    send_event(&events, DeployEvent::Cloning(ipns_name.clone()));
+                                    let (_tmp_dir, workdir) = clone_deployment(
+                                        &ipfs_api_server,
+                                        &gateway_url,
+                                        &ipns_name,
+                                        temp_dir.as_ref().map(PathBuf::as_path),
+                                        trusted_signers.as_ref(),
+                                    )?;
+                                    timings.finish("clone");
+                                    read_image_ref(&workdir)
+                                }
+                            }
+                        }
+                    })
+                    .and_then(move |image_ref| {
+                        info!("Pulling {}", image_ref);
+                        runtime.pull(image_ref.clone()).map(move |_ok| {
+                            timings.finish("pull");
+                            image_ref
+                        })
+                    }),
+                )
+            }
+        };
         Box::new(
-            future::result(TempDir::new())
-                .map_err(|err| -> Error { err.into() })
-                .and_then(move |tmp_dir| {
-                    debug!("Cloning git repo with dns record {}", dns_record_name);
-                    let git_clone_output = Command::new("git")
-                        .arg("clone")
-                        .arg(format!("http://localhost:8080/ipns/{}", dns_record_name))
-                        .arg("--single-branch")
-                        .current_dir(tmp_dir.path())
-                        .output()?;
-                    debug!("{:?}", git_clone_output);
-                    let mut repo_path = tmp_dir.path().to_path_buf();
-                    repo_path.push(dns_record_name.to_string());
-                    let repo = Repository::discover(repo_path)?;
-                    Ok((tmp_dir, repo, format!("{}:latest", dns_record_name)))
-                })
-                .and_then(|(tmp_dir, repo, image_name)| {
-                    docker::build_image(&repo, image_name.clone()).map(|x| (x, image_name, tmp_dir))
-                })
-                .and_then(|(ok, image_name, _tmp_dir)| {
-                    debug!("Creating docker container");
-                    docker::create_new_container(
-                        image_name.clone(),
-                        None,
-                        Some(HostConfig {
-                            port_bindings,
-                            publish_all_ports: None,
-                        }),
-                    )
-                    .map(|x| (x, image_name))
+            image
+                .and_then({
+                    let runtime = runtime.clone();
+                    let deployment = deployment.clone();
+                    let timings = timings.clone();
+                    move |image_name| match cid {
+                        Some(cid) if !dry_run => {
+                            debug!("Tagging {} as {}:{}", image_name, deployment, cid);
+                            future::Either::A(
+                                runtime
+                                    .tag(image_name.clone(), deployment, cid)
+                                    .map(move |_tagged| {
+                                        timings.finish("tag");
+                                        image_name
+                                    }),
+                            )
+                        }
+                        _ => future::Either::B(future::ok(image_name)),
+                    }
                 })
-                .and_then(|(create_container_response, image_name)| {
-                    debug!("Listing docker images");
-                    docker::list_images()
-                        .map(|images| (create_container_response, image_name, images))
+                .and_then({
+                    let timings = timings.clone();
+                    move |image_name| -> BoxFuture<bool> {
+                        if dry_run {
+                            return plan.dry_run(image_name);
+                        }
+                        Box::new(plan.run(image_name).map(move |deployed| {
+                            timings.finish("start");
+                            deployed
+                        }))
+                    }
                 })
-                .and_then(|(create_container_response, image_name, images)| {
-                    debug!("Listing existing docker images");
-                    docker::list_containers().map(|containers| {
-                        (create_container_response, image_name, images, containers)
-                    })
+                .and_then(move |deployed| {
+                    if dry_run {
+                        return future::Either::A(future::ok(deployed));
+                    }
+                    let pruned = prune_cid_tags(runtime, deployment.clone(), keep_images);
+                    future::Either::B(pruned.then(move |res| {
+                        let pruned_tags = match res {
+                            Ok(pruned_tags) => pruned_tags,
+                            Err(err) => {
+                                warn!("Failed to prune old images {:?}", err);
+                                Vec::new()
+                            }
+                        };
+                        timings.finish("prune");
+                        if let Some((ipfs_api_server, cid)) = pin {
+                            let cid = cid.as_ref().map(String::as_str);
+                            update_pins(&ipfs_api_server, &deployment, cid, &pruned_tags);
+                            timings.finish("pin");
+                        }
+                        let total = timings.total();
+                        log!(
+                            timings.level,
+                            "Deploy took {}.{:03}s",
+                            total.as_secs(),
+                            total.subsec_millis()
+                        );
+                        Ok(deployed)
+                    }))
+                }),
+        )
+    }
+
+    /// Builds and runs straight from a local directory, e.g. a USB stick on an air-gapped
+    /// device, skipping DNS, IPFS, and cloning entirely. Runs as the first target.
+    pub fn try_deploy_from_dir(&self, path: &Path) -> BoxFuture<bool> {
+        let target = &self.config.targets[0];
+        let container_name = match target.dns_record_name.get("_dnslink.".len()..) {
+            Some(dns_record_name) => String::from(dns_record_name),
+            None => match path.file_name().and_then(|name| name.to_str()) {
+                Some(dir_name) => dir_name.to_lowercase(),
+                None => return Box::new(future::ok(false)),
+            },
+        };
+        let runtime = self.runtime();
+        let plan = self.container_plan(target, container_name.clone(), runtime.clone());
+        let image_name = format!("{}:latest", container_name);
+        debug!("Building from local directory {}", path.display());
+        Box::new(
+            runtime
+                .build(path.to_path_buf(), image_name.clone())
+                .and_then(move |_ok| plan.run(image_name)),
+        )
+    }
+
+    /// Looks up the record of `target` and compares it to what is running.
+    pub fn try_status(&self, target: &config::DeployTarget) -> BoxFuture<DeployStatus> {
+        let dns_record_name = target.dns_record_name.clone();
+        let deployment = String::from(
+            target
+                .dns_record_name
+                .get("_dnslink.".len()..)
+                .unwrap_or_default(),
+        );
+        let runtime = self.runtime();
+        Box::new(
+            self.try_lookup_txt_record(target)
+                .join3(runtime.list(), runtime.list_images())
+                .map(move |(record, containers, images)| {
+                    let published_cid = record.as_ref().and_then(DnsTxtRecordResponse::as_hash);
+                    deploy_status(dns_record_name, &deployment, published_cid, containers, images)
+                }),
+        )
+    }
+
+    /// Looks up each target once and deploys the ones not running what their record points to,
+    /// for when a scheduler like cron runs the daemon rather than it running on its own. Returns
+    /// whether anything was deployed, or the first error once every target has had its turn.
+    pub fn run_once(&self) -> Result<bool> {
+        let mut sys = actix::System::new("lrad-daemon");
+        let mut deployed_any = false;
+        let mut first_err = None;
+        for target in self.targets() {
+            let res = sys.block_on(self.try_status(target)).and_then(|status| {
+                let cid = match (status.state, status.published_cid) {
+                    (DeployState::NoRecord, _) | (_, None) => {
+                        warn!(
+                            "{} has no TXT record, or none pointing at a CID, not deploying.",
+                            target.dns_record_name
+                        );
+                        return Ok(false);
+                    }
+                    (DeployState::UpToDate, _) => {
+                        info!("{} is up to date.", target.dns_record_name);
+                        return Ok(false);
+                    }
+                    (_, Some(cid)) => cid,
+                };
+                info!("Deploying {} for {}.", cid, target.dns_record_name);
+                let res = sys.block_on(self.try_deploy(target, Some(&cid)));
+                let notifier = self.deploy_notifier(target, Some(&cid));
+                // Notifying only logs its failures
+                let _ = sys.block_on(notifier.notify(&res));
+                res
+            });
+            match res {
+                Ok(deployed) => deployed_any |= deployed,
+                Err(err) => {
+                    error!("Error while deploying {} {:?}", target.dns_record_name, err);
+                    first_err = first_err.or(Some(err));
+                }
+            }
+        }
+        match first_err {
+            Some(err) => Err(err),
+            None => Ok(deployed_any),
+        }
+    }
+
+    /// The CIDs that images of `target` are kept around for, most recently built first.
+    pub fn deployed_cids(&self, target: &config::DeployTarget) -> BoxFuture<Vec<String>> {
+        let deployment = String::from(
+            target
+                .dns_record_name
+                .get("_dnslink.".len()..)
+                .unwrap_or_default(),
+        );
+        Box::new(self.runtime().list_images().map(move |images| {
+            let prefix = format!("{}:", deployment);
+            cid_tags(images, &deployment)
+                .into_iter()
+                .map(|(_, tag)| String::from(&tag[prefix.len()..]))
+                .collect()
+        }))
+    }
+
+    /// Runs an earlier image of `target` in place of the current one: the one tagged with `to`,
+    /// or else the one built before the image that is running now. Returns the CID rolled back
+    /// to. The daemon deploys the latest record again once it changes or the daemon restarts.
+    pub fn try_rollback(
+        &self,
+        target: &config::DeployTarget,
+        to: Option<&str>,
+    ) -> BoxFuture<String> {
+        let to = to.map(String::from);
+        let deployment = match target.dns_record_name.get("_dnslink.".len()..) {
+            Some(deployment) => String::from(deployment),
+            None => {
+                return Box::new(future::err(
+                    ErrorKind::RollbackImageNotFound(target.dns_record_name.clone(), to).into(),
+                ))
+            }
+        };
+        let runtime = self.runtime();
+        let plan = self.container_plan(target, deployment.clone(), runtime.clone());
+        Box::new(
+            runtime
+                .list_images()
+                .join(runtime.list())
+                .and_then(move |(images, containers)| {
+                    let current_image_id = running_image_id(containers, &deployment);
+                    let tags = cid_tags(images, &deployment);
+                    match rollback_tag(
+                        &tags,
+                        current_image_id.as_ref().map(String::as_str),
+                        &deployment,
+                        to.as_ref().map(String::as_str),
+                    ) {
+                        Some(tag) => {
+                            let cid = String::from(&tag[deployment.len() + 1..]);
+                            Ok((tag, cid))
+                        }
+                        None => Err(ErrorKind::RollbackImageNotFound(deployment, to).into()),
+                    }
                 })
-                .and_then(
-                    move |(create_container_response, image_name, images, containers)| {
-                        debug!("Removing old docker container(s)");
-                        let removable_image_ids: Vec<String> = images
-                            .iter()
-                            .filter(|image| image.repo_tags.contains(&image_name))
-                            .map(|image| image.id.clone())
-                            .collect();
-
-                        // TODO: This currently deletes all docker containers, need to selectively delete the ones of interest.
-                        // let containers_to_remove: Vec<docker::ListContainersResponse> = containers.iter().filter(|container| {
-                        //     container.id != create_container_response.id // && removable_image_ids.contains(&container.image)
-                        // }).collect();
-                        stream::iter_ok(containers)
-                            .and_then(|container| {
-                                docker::force_remove_running_container(container.id.clone())
-                            })
-                            .collect()
-                            .map(|x| (x, create_container_response))
-                    },
-                )
-                .and_then(|(_removed, create_container_response)| {
-                    debug!("Starting new docker container");
-                    docker::start_container(create_container_response.id)
+                .and_then(move |(tag, cid)| {
+                    info!("Rolling back to {}", tag);
+                    plan.run(tag).map(move |_deployed| cid)
                 }),
         )
     }
 }
+
+/// How a published deployment compares to the local repo, for `lrad verify`.
+#[derive(Debug, PartialEq)]
+pub enum Verification {
+    /// What the record points to has the tree of the local HEAD
+    Match { cid: String, tree: String },
+    /// Either the record points to a stale CID or the content came back different
+    Mismatch {
+        cid: String,
+        published_tree: String,
+        local_tree: String,
+    },
+    /// The record doesn't point to a CID
+    NotPublished,
+}
+
+fn verification(cid: String, published_tree: git2::Oid, local_tree: git2::Oid) -> Verification {
+    if published_tree == local_tree {
+        Verification::Match {
+            cid,
+            tree: local_tree.to_string(),
+        }
+    } else {
+        Verification::Mismatch {
+            cid,
+            published_tree: published_tree.to_string(),
+            local_tree: local_tree.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for Verification {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Verification::Match { cid, tree } => {
+                write!(f, "match: {} has tree {}, like the local HEAD", cid, tree)
+            }
+            Verification::Mismatch {
+                cid,
+                published_tree,
+                local_tree,
+            } => write!(
+                f,
+                "mismatch: {} has tree {}, but the local HEAD has tree {}",
+                cid, published_tree, local_tree
+            ),
+            Verification::NotPublished => write!(f, "the DNS link record doesn't point to a CID"),
+        }
+    }
+}
+
+/// How what is running compares to what is published.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployState {
+    UpToDate,
+    /// Running something other than what the record points to, e.g. while a deploy is underway
+    Behind,
+    /// Nothing of the target is running
+    Failed,
+    /// The record doesn't point to a CID, so there is nothing to compare what is running to
+    NoRecord,
+}
+
+/// What a target has deployed versus what its record points to now, for `lrad status`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DeployStatus {
+    pub dns_record_name: String,
+    pub published_cid: Option<String>,
+    /// The CID the image of the running container was tagged with, if any
+    pub deployed_cid: Option<String>,
+    pub state: DeployState,
+}
+
+impl std::fmt::Display for DeployStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let state = match self.state {
+            DeployState::UpToDate => "up to date",
+            DeployState::Behind => "behind",
+            DeployState::Failed => "failed, nothing is running",
+            DeployState::NoRecord => "no record pointing at a CID",
+        };
+        writeln!(f, "{}: {}", self.dns_record_name, state)?;
+        writeln!(
+            f,
+            "  published: {}",
+            self.published_cid.as_ref().map(String::as_str).unwrap_or("-")
+        )?;
+        write!(
+            f,
+            "  deployed:  {}",
+            self.deployed_cid.as_ref().map(String::as_str).unwrap_or("-")
+        )
+    }
+}
+
+/// The image of the container running for `deployment`, if any.
+fn running_image_id(
+    containers: Vec<docker::ListContainersResponse>,
+    deployment: &str,
+) -> Option<String> {
+    containers
+        .into_iter()
+        .find(|container| {
+            container.state == "running"
+                && container
+                    .labels
+                    .as_ref()
+                    .and_then(|labels| labels.get(docker::DEPLOYMENT_LABEL))
+                    .map(|label| label == deployment)
+                    .unwrap_or(false)
+        })
+        .map(|container| container.image_id)
+}
+
+/// Compares the container running for `deployment`, if any, to the published CID.
+fn deploy_status(
+    dns_record_name: String,
+    deployment: &str,
+    published_cid: Option<String>,
+    containers: Vec<docker::ListContainersResponse>,
+    images: Vec<docker::ListImagesResponse>,
+) -> DeployStatus {
+    let running_image_id = running_image_id(containers, deployment);
+    let deployed_cid = running_image_id.as_ref().and_then(|running_image_id| {
+        cid_tags(images, deployment)
+            .into_iter()
+            .find(|(id, _)| id == running_image_id)
+            .map(|(_, tag)| String::from(&tag[deployment.len() + 1..]))
+    });
+    let state = if published_cid.is_none() {
+        DeployState::NoRecord
+    } else if running_image_id.is_none() {
+        DeployState::Failed
+    } else if deployed_cid != published_cid {
+        DeployState::Behind
+    } else {
+        DeployState::UpToDate
+    };
+    DeployStatus {
+        dns_record_name,
+        published_cid,
+        deployed_cid,
+        state,
+    }
+}
+
+/// Keeps track of the deploy in progress, so that it can be abandoned once a newer one starts.
+#[derive(Default)]
+pub struct DeploySlot {
+    abort: Option<oneshot::Sender<()>>,
+}
+
+impl DeploySlot {
+    /// Aborts the deploy in progress, if any, and makes `deploy` the one in progress. The returned
+    /// future resolves to `None` if `deploy` is itself aborted later on.
+    ///
+    /// Aborting drops the deploy future along with whatever request it is waiting on, so Docker
+    /// cancels a build when its connection goes away. A container that was already created is
+    /// labeled with the deployment and gets removed by the next deploy.
+    pub fn start<T: 'static>(&mut self, deploy: BoxFuture<T>) -> BoxFuture<Option<T>> {
+        self.abort();
+        let (abort, aborted) = oneshot::channel();
+        self.abort = Some(abort);
+        // The sender is only dropped without sending once the deploy is no longer tracked, which
+        // must not abort it.
+        let aborted = aborted.or_else(|_canceled| future::empty::<(), Error>());
+        Box::new(deploy.select2(aborted).then(|res| match res {
+            Ok(future::Either::A((deployed, _))) => Ok(Some(deployed)),
+            Ok(future::Either::B(((), _))) => Ok(None),
+            Err(future::Either::A((err, _))) | Err(future::Either::B((err, _))) => Err(err),
+        }))
+    }
+
+    /// Aborts the deploy in progress. Nothing happens if it already finished.
+    pub fn abort(&mut self) {
+        if let Some(abort) = self.abort.take() {
+            let _ = abort.send(());
+        }
+    }
+}
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(300);
+
+const DEFAULT_GATEWAY_URL: &str = "http://localhost:8080";
+
+const DEFAULT_DNS_LOOKUP_ATTEMPTS: u32 = 3;
+const DEFAULT_DNS_RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+/// Scales `base` by a random factor within ±`jitter_percent`%.
+fn jittered<R: Rng>(base: Duration, jitter_percent: u8, rng: &mut R) -> Duration {
+    let jitter = f64::from(jitter_percent.min(100)) / 100.0;
+    if jitter == 0.0 {
+        return base;
+    }
+    let factor = rng.gen_range(1.0 - jitter, 1.0 + jitter);
+    let millis = (base.as_secs() * 1000 + u64::from(base.subsec_millis())) as f64 * factor;
+    Duration::from_millis(millis as u64)
+}
+
+/// Times the stages of a single deploy, logging each one as it finishes.
+struct DeployTimings {
+    level: log::Level,
+    started: Instant,
+    /// How long after the start of the deploy each stage finished
+    finished: RefCell<Vec<(&'static str, Duration)>>,
+}
+
+impl DeployTimings {
+    fn new(level: log::Level) -> Self {
+        Self {
+            level,
+            started: Instant::now(),
+            finished: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Marks `stage` as finished, i.e. as having taken the time since the previous one finished.
+    fn finish(&self, stage: &'static str) {
+        let elapsed = self.started.elapsed();
+        let mut finished = self.finished.borrow_mut();
+        let previous = finished.last().map(|(_, at)| *at).unwrap_or_default();
+        let took = elapsed - previous;
+        log!(
+            self.level,
+            "{} took {}.{:03}s",
+            stage,
+            took.as_secs(),
+            took.subsec_millis()
+        );
+        finished.push((stage, elapsed));
+    }
+
+    /// How long each finished stage took, in the order they finished.
+    fn stages(&self) -> Vec<(&'static str, Duration)> {
+        let mut previous = Duration::default();
+        self.finished
+            .borrow()
+            .iter()
+            .map(|(stage, at)| {
+                let took = *at - previous;
+                previous = *at;
+                (*stage, took)
+            })
+            .collect()
+    }
+
+    fn total(&self) -> Duration {
+        self.finished
+            .borrow()
+            .last()
+            .map(|(_, at)| *at)
+            .unwrap_or_default()
+    }
+}
+
+/// How long a new container without a healthcheck has to stay up to be considered healthy.
+const HEALTH_SETTLE_TIME: Duration = Duration::from_secs(10);
+const DEFAULT_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(60);
+
+const DEFAULT_MAX_CONCURRENT_DOCKER_OPS: usize = 2;
+
+struct ContainerPlan {
+    runtime: Rc<ContainerRuntime>,
+    container_name: String,
+    replace: bool,
+    max_concurrent_docker_ops: usize,
+    health_check_timeout: Duration,
+    host_config: HostConfig,
+    env: Vec<String>,
+}
+
+impl ContainerPlan {
+    fn run(self, image_name: String) -> BoxFuture<bool> {
+        let mut labels = HashMap::with_capacity(1);
+        labels.insert(
+            String::from(docker::DEPLOYMENT_LABEL),
+            self.container_name.clone(),
+        );
+        let request = CreateContainerRequest {
+            image: image_name,
+            labels,
+            env: self.env,
+            host_config: Some(self.host_config),
+        };
+        if self.replace {
+            replace_container(
+                self.runtime,
+                request,
+                self.container_name,
+                self.max_concurrent_docker_ops,
+                self.health_check_timeout,
+            )
+        } else {
+            recreate_container(
+                self.runtime,
+                request,
+                self.container_name,
+                self.max_concurrent_docker_ops,
+                self.health_check_timeout,
+            )
+        }
+    }
+
+    /// Logs what `run` would do without touching any container. Resolves to false, as nothing
+    /// was deployed.
+    fn dry_run(self, image_name: String) -> BoxFuture<bool> {
+        info!("Would run {} as {}", image_name, self.container_name);
+        Box::new(
+            containers_to_remove(
+                self.runtime,
+                String::new(),
+                image_name,
+                self.container_name,
+            )
+            .map(|containers_to_remove| {
+                for container in containers_to_remove {
+                    info!("Would remove container {} ({})", container.id, container.image);
+                }
+                false
+            }),
+        )
+    }
+}
+
+const DEFAULT_KEEP_IMAGES: usize = 3;
+
+/// The `<deployment>:<cid>` tags along with the id of their image, most recently built first.
+fn cid_tags(images: Vec<docker::ListImagesResponse>, deployment: &str) -> Vec<(String, String)> {
+    let prefix = format!("{}:", deployment);
+    let latest = format!("{}:latest", deployment);
+    let mut tags: Vec<(i64, String, String)> = images
+        .into_iter()
+        .flat_map(|image| {
+            let created = image.created;
+            let id = image.id;
+            image
+                .repo_tags
+                .into_iter()
+                .map(move |tag| (created, id.clone(), tag))
+        })
+        .filter(|(_, _, tag)| tag.starts_with(&prefix) && *tag != latest)
+        .collect();
+    tags.sort_by(|a, b| b.0.cmp(&a.0));
+    tags.into_iter().map(|(_, id, tag)| (id, tag)).collect()
+}
+
+/// The `<deployment>:<cid>` tags beyond the `keep` most recently built, oldest last.
+fn stale_cid_tags(
+    images: Vec<docker::ListImagesResponse>,
+    deployment: &str,
+    keep: usize,
+) -> Vec<String> {
+    cid_tags(images, deployment)
+        .into_iter()
+        .skip(keep)
+        .map(|(_, tag)| tag)
+        .collect()
+}
+
+/// The tag to roll back to among `tags`, as listed by `cid_tags`: the one for `to` if given, or
+/// else the most recent one of an image other than the current one and built before it.
+fn rollback_tag(
+    tags: &[(String, String)],
+    current_image_id: Option<&str>,
+    deployment: &str,
+    to: Option<&str>,
+) -> Option<String> {
+    if let Some(cid) = to {
+        let tag = format!("{}:{}", deployment, cid);
+        return tags.iter().find(|(_, known)| *known == tag).map(|_| tag);
+    }
+    let current = current_image_id
+        .and_then(|current_image_id| tags.iter().position(|(id, _)| id == current_image_id));
+    match current {
+        Some(current) => tags[current..]
+            .iter()
+            .find(|(id, _)| *id != tags[current].0)
+            .map(|(_, tag)| tag.clone()),
+        None => tags.get(1).map(|(_, tag)| tag.clone()),
+    }
+}
+
+/// Untags all but the `keep` most recent CID-tagged images of `deployment`, letting Docker delete
+/// the ones that no longer have a name. Resolves to the tags removed.
+fn prune_cid_tags(
+    runtime: Rc<ContainerRuntime>,
+    deployment: String,
+    keep: usize,
+) -> BoxFuture<Vec<String>> {
+    Box::new(runtime.list_images().and_then(move |images| {
+        let stale_tags = stale_cid_tags(images, &deployment, keep);
+        stream::iter_ok(stale_tags.clone())
+            .map(move |tag| {
+                debug!("Removing old image {}", tag);
+                runtime.remove_image(tag)
+            })
+            .buffer_unordered(1)
+            .collect()
+            .map(move |_removed| stale_tags)
+    }))
+}
+
+/// The CIDs that `<deployment>:<cid>` tags were named after.
+fn pruned_cids<'a>(deployment: &str, pruned_tags: &'a [String]) -> Vec<&'a str> {
+    pruned_tags
+        .iter()
+        .filter_map(|tag| tag.get(deployment.len() + 1..))
+        .collect()
+}
+
+/// Pins the deployed `cid` on the local IPFS node and unpins the CIDs of the images pruned for
+/// `deployment`. Failures are only logged, the deploy itself went fine.
+fn update_pins(
+    ipfs_api_server: &ipfs::IpfsApiServerConfig,
+    deployment: &str,
+    cid: Option<&str>,
+    pruned_tags: &[String],
+) {
+    if let Some(cid) = cid {
+        debug!("Pinning {}", cid);
+        if let Err(err) = ipfs::pin_add(ipfs_api_server, cid) {
+            warn!("Failed to pin {} {:?}", cid, err);
+        }
+    }
+    for cid in pruned_cids(deployment, pruned_tags) {
+        debug!("Unpinning {}", cid);
+        if let Err(err) = ipfs::pin_rm(ipfs_api_server, cid) {
+            warn!("Failed to unpin {} {:?}", cid, err);
+        }
+    }
+}
+
+/// Containers from earlier deploys of `deployment`, recognized by their label or by running one
+/// of the images that were tagged for it. Unrelated containers are left alone.
+fn old_deployment_containers(
+    containers: Vec<docker::ListContainersResponse>,
+    new_container_id: &str,
+    deployment: &str,
+    removable_image_ids: &[String],
+) -> Vec<docker::ListContainersResponse> {
+    containers
+        .into_iter()
+        .filter(|container| container.id != new_container_id)
+        .filter(|container| {
+            let labeled = container
+                .labels
+                .as_ref()
+                .and_then(|labels| labels.get(docker::DEPLOYMENT_LABEL))
+                .map(|label| label == deployment)
+                .unwrap_or(false);
+            labeled || removable_image_ids.contains(&container.image_id)
+        })
+        .collect()
+}
+
+/// Removes the containers of earlier deploys of `deployment`, except for the new one.
+fn remove_old_containers(
+    runtime: Rc<ContainerRuntime>,
+    new_container_id: String,
+    image_name: String,
+    deployment: String,
+    max_concurrent_docker_ops: usize,
+) -> BoxFuture<()> {
+    Box::new(
+        containers_to_remove(runtime.clone(), new_container_id, image_name, deployment).and_then(
+            move |containers_to_remove| {
+                debug!("Removing old docker container(s)");
+                stream::iter_ok(containers_to_remove)
+                    .map(move |container| runtime.remove(container.id.clone()))
+                    .buffer_unordered(max_concurrent_docker_ops)
+                    .collect()
+                    .map(|_removed| ())
+            },
+        ),
+    )
+}
+
+/// The containers of earlier deploys of `deployment` that a deploy of `image_name` replaces.
+fn containers_to_remove(
+    runtime: Rc<ContainerRuntime>,
+    new_container_id: String,
+    image_name: String,
+    deployment: String,
+) -> BoxFuture<Vec<docker::ListContainersResponse>> {
+    debug!("Listing docker images");
+    Box::new(
+        runtime
+            .list_images()
+            .and_then({
+                let runtime = runtime.clone();
+                move |images| {
+                    debug!("Listing existing docker containers");
+                    runtime.list().map(|containers| (images, containers))
+                }
+            })
+            .map(move |(images, containers)| {
+                let removable_image_ids: Vec<String> = images
+                    .iter()
+                    .filter(|image| image.repo_tags.contains(&image_name))
+                    .map(|image| image.id.clone())
+                    .collect();
+                old_deployment_containers(
+                    containers,
+                    &new_container_id,
+                    &deployment,
+                    &removable_image_ids,
+                )
+            }),
+    )
+}
+
+/// Creates and starts the new container, and only removes the old ones once it is healthy. If it
+/// never becomes healthy, it is removed instead and the old ones keep running. A new container
+/// that can't start alongside the old ones gets its turn once they are gone, and is removed if it
+/// still doesn't start or become healthy.
+fn recreate_container(
+    runtime: Rc<ContainerRuntime>,
+    request: CreateContainerRequest,
+    deployment: String,
+    max_concurrent_docker_ops: usize,
+    health_check_timeout: Duration,
+) -> BoxFuture<bool> {
+    debug!("Creating docker container");
+    let image_name = request.image.clone();
+    Box::new(
+        runtime
+            .create(None, request)
+            .and_then({
+                let runtime = runtime.clone();
+                move |container_id: String| {
+                    debug!("Starting new docker container");
+                    runtime
+                        .start(container_id.clone())
+                        .map(move |started| (container_id, started))
+                }
+            })
+            .and_then(move |(container_id, started)| -> BoxFuture<bool> {
+                if started {
+                    debug!("Waiting for new docker container to become healthy");
+                    let healthy = runtime::wait_until_healthy(
+                        runtime.clone(),
+                        container_id.clone(),
+                        HEALTH_SETTLE_TIME,
+                        health_check_timeout,
+                    );
+                    Box::new(healthy.then(move |res| match res {
+                        Ok(()) => future::Either::A(
+                            remove_old_containers(
+                                runtime,
+                                container_id,
+                                image_name,
+                                deployment,
+                                max_concurrent_docker_ops,
+                            )
+                            .map(|()| true),
+                        ),
+                        Err(err) => {
+                            warn!("New docker container is not healthy, keeping the old one(s)");
+                            future::Either::B(
+                                runtime
+                                    .remove(container_id)
+                                    .then(move |_| Err::<bool, Error>(err)),
+                            )
+                        }
+                    }))
+                } else {
+                    // Most likely the old container still holds a published host port, so it
+                    // has to go before the new one can start.
+                    warn!("New docker container could not start alongside the old one(s), removing them first");
+                    Box::new(
+                        remove_old_containers(
+                            runtime.clone(),
+                            container_id.clone(),
+                            image_name,
+                            deployment,
+                            max_concurrent_docker_ops,
+                        )
+                        .and_then({
+                            let runtime = runtime.clone();
+                            move |()| {
+                                debug!("Starting new docker container");
+                                runtime
+                                    .start(container_id.clone())
+                                    .map(move |started| (container_id, started))
+                            }
+                        })
+                        .and_then(move |(container_id, started)| -> BoxFuture<bool> {
+                            if !started {
+                                return Box::new(runtime.remove(container_id.clone()).then(
+                                    move |_| {
+                                        Err::<bool, Error>(
+                                            ErrorKind::ContainerNotRunning(container_id).into(),
+                                        )
+                                    },
+                                ));
+                            }
+                            let healthy = runtime::wait_until_healthy(
+                                runtime.clone(),
+                                container_id.clone(),
+                                HEALTH_SETTLE_TIME,
+                                health_check_timeout,
+                            );
+                            Box::new(healthy.then(move |res| match res {
+                                Ok(()) => future::Either::A(future::ok(true)),
+                                Err(err) => future::Either::B(
+                                    runtime
+                                        .remove(container_id)
+                                        .then(move |_| Err::<bool, Error>(err)),
+                                ),
+                            }))
+                        }),
+                    )
+                }
+            }),
+    )
+}
+
+/// Starts the new container under a temporary name and only swaps it in for the old ones once it
+/// has stayed up, so the old containers keep serving if the new one fails to start. A new
+/// container publishing the same host ports as an old one can't start alongside it, which fails
+/// the deploy rather than taking the old one down first.
+fn replace_container(
+    runtime: Rc<ContainerRuntime>,
+    request: CreateContainerRequest,
+    container_name: String,
+    max_concurrent_docker_ops: usize,
+    health_check_timeout: Duration,
+) -> BoxFuture<bool> {
+    let next_container_name = format!("{}-next", container_name);
+    let image_name = request.image.clone();
+    Box::new(
+        runtime
+            .remove(next_container_name.clone())
+            .and_then({
+                let runtime = runtime.clone();
+                move |_| {
+                    debug!("Creating docker container {}", next_container_name);
+                    runtime.create(Some(next_container_name), request)
+                }
+            })
+            .and_then({
+                let runtime = runtime.clone();
+                move |container_id: String| {
+                    debug!("Starting new docker container");
+                    runtime
+                        .start(container_id.clone())
+                        .map(move |started| (container_id, started))
+                }
+            })
+            .and_then({
+                let runtime = runtime.clone();
+                move |(container_id, started)| -> BoxFuture<String> {
+                    if !started {
+                        warn!("New docker container could not start alongside the old one, keeping the old one");
+                        return Box::new(runtime.remove(container_id.clone()).then(move |_| {
+                            Err::<String, Error>(ErrorKind::ContainerNotRunning(container_id).into())
+                        }));
+                    }
+                    debug!("Waiting for new docker container to become healthy");
+                    Box::new(
+                        runtime::wait_until_healthy(
+                            runtime.clone(),
+                            container_id.clone(),
+                            HEALTH_SETTLE_TIME,
+                            health_check_timeout,
+                        )
+                        .then(move |res| match res {
+                            Ok(()) => future::Either::A(future::ok(container_id)),
+                            Err(err) => {
+                                warn!("New docker container is not healthy, keeping the old one");
+                                future::Either::B(
+                                    runtime
+                                        .remove(container_id)
+                                        .then(move |_| Err::<String, Error>(err)),
+                                )
+                            }
+                        }),
+                    )
+                }
+            })
+            .and_then({
+                let runtime = runtime.clone();
+                let container_name = container_name.clone();
+                move |container_id| {
+                    remove_old_containers(
+                        runtime,
+                        container_id.clone(),
+                        image_name,
+                        container_name,
+                        max_concurrent_docker_ops,
+                    )
+                    .map(move |()| container_id)
+                }
+            })
+            .and_then(move |container_id| {
+                debug!("Renaming new docker container to {}", container_name);
+                runtime
+                    .rename(container_id.clone(), container_name.clone())
+                    .and_then(move |renamed| {
+                        if renamed {
+                            Ok(true)
+                        } else {
+                            Err(ErrorKind::ContainerRenameFailed(container_id, container_name)
+                                .into())
+                        }
+                    })
+            }),
+    )
+}
@@ -0,0 +1,118 @@
+use actix_web::client;
+use futures::future;
+use futures::prelude::*;
+use std::time::Duration;
+
+use crate::error::{BoxFuture, Result};
+
+/// What is POSTed to the configured `notify_url` once a deploy finishes.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct DeployReport {
+    pub device_id: String,
+    pub dns_record: String,
+    pub cid: Option<String>,
+    /// "deployed" or "failed"
+    pub status: &'static str,
+    pub error: Option<String>,
+}
+
+/// Reports how a single deploy went, if there is anywhere to report it to.
+pub struct DeployNotifier {
+    pub(crate) url: Option<String>,
+    pub(crate) device_id: String,
+    pub(crate) dns_record: String,
+    pub(crate) cid: Option<String>,
+}
+
+impl DeployNotifier {
+    fn report(self, res: &Result<bool>) -> DeployReport {
+        let (status, error) = match res {
+            Ok(true) => ("deployed", None),
+            Ok(false) => ("failed", Some(String::from("nothing was deployed"))),
+            Err(err) => ("failed", Some(err.to_string())),
+        };
+        DeployReport {
+            device_id: self.device_id,
+            dns_record: self.dns_record,
+            cid: self.cid,
+            status,
+            error,
+        }
+    }
+
+    /// Sends the report of the finished deploy. Failing to send it is only logged, so that it
+    /// never affects the deploy itself.
+    pub fn notify(mut self, res: &Result<bool>) -> BoxFuture<()> {
+        let url = match self.url.take() {
+            Some(url) => url,
+            None => return Box::new(future::ok(())),
+        };
+        let report = self.report(res);
+        debug!("Sending deploy report {:?} to {}", report, url);
+        let request = match client::post(&url)
+            .timeout(Duration::from_secs(30))
+            .json(report)
+        {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("Failed to build deploy report for {} {:?}", url, err);
+                return Box::new(future::ok(()));
+            }
+        };
+        Box::new(request.send().then(move |res| {
+            match res {
+                Ok(ref response) if response.status().is_success() => {
+                    debug!("Sent deploy report to {}", url)
+                }
+                Ok(response) => warn!(
+                    "Deploy report to {} was answered with {}",
+                    url,
+                    response.status()
+                ),
+                Err(err) => warn!("Failed to send deploy report to {} {:?}", url, err),
+            }
+            Ok(())
+        }))
+    }
+}
+
+/// The hostname, which tells devices apart in deploy reports unless `device_id` is configured.
+pub(crate) fn hostname() -> String {
+    std::fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| std::fs::read_to_string("/etc/hostname"))
+        .map(|hostname| String::from(hostname.trim()))
+        .unwrap_or_else(|_| String::from("unknown"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorKind;
+
+    fn notifier() -> DeployNotifier {
+        DeployNotifier {
+            url: None,
+            device_id: String::from("pi-1"),
+            dns_record: String::from("_dnslink.app.example.com"),
+            cid: Some(String::from("QmNew")),
+        }
+    }
+
+    #[test]
+    fn failed_deploy_is_reported_with_its_error() {
+        let res = Err(ErrorKind::ContainerUnhealthy(String::from("abc")).into());
+        assert_eq!(
+            notifier().report(&res),
+            DeployReport {
+                device_id: String::from("pi-1"),
+                dns_record: String::from("_dnslink.app.example.com"),
+                cid: Some(String::from("QmNew")),
+                status: "failed",
+                error: Some(String::from("container abc is unhealthy")),
+            }
+        );
+        assert_eq!(notifier().report(&Ok(true)).status, "deployed");
+        assert_eq!(notifier().report(&Ok(true)).error, None);
+        assert_eq!(notifier().report(&Ok(false)).status, "failed");
+    }
+}
@@ -0,0 +1,42 @@
+use futures::future::{self, Loop};
+use futures::prelude::*;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+use crate::error::Error;
+
+/// Runs the future produced by `f` up to `attempts` times, waiting `base_delay` before the first
+/// retry and doubling the wait for each one after that. Only failures that `is_transient` accepts
+/// are retried; `what` names the operation in the log.
+pub fn retry<F, Fut>(
+    what: &'static str,
+    attempts: u32,
+    base_delay: Duration,
+    is_transient: fn(&Error) -> bool,
+    f: F,
+) -> impl Future<Item = Fut::Item, Error = Error>
+where
+    F: FnMut() -> Fut,
+    Fut: IntoFuture<Error = Error>,
+{
+    future::loop_fn((f, 1), move |(mut f, attempt)| {
+        f().into_future().then(move |res| match res {
+            Ok(item) => future::Either::A(future::ok(Loop::Break(item))),
+            Err(err) => {
+                if !(attempt < attempts && is_transient(&err)) {
+                    return future::Either::A(future::err(err));
+                }
+                let delay = base_delay * 2u32.saturating_pow(attempt - 1);
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {:?}",
+                    what, attempt, attempts, delay, err
+                );
+                future::Either::B(
+                    Delay::new(Instant::now() + delay)
+                        .map_err(|err| Error::from(err))
+                        .map(move |_| Loop::Continue((f, attempt + 1))),
+                )
+            }
+        })
+    })
+}
@@ -0,0 +1,445 @@
+use futures::future::{self, Loop};
+use futures::prelude::*;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use tokio_timer::Delay;
+
+use crate::docker::{
+    CreateContainerRequest, InspectContainerResponse, ListContainersResponse, ListImagesResponse,
+};
+use crate::error::{BoxFuture, Error, ErrorKind, Result};
+
+/// What a deploy needs from whatever builds and runs its containers. The Docker API is one
+/// implementation, `CommandRuntime` drives a CLI instead.
+pub trait ContainerRuntime {
+    /// Builds the image `image_name` from the build context at `context`.
+    fn build(&self, context: PathBuf, image_name: String) -> BoxFuture<bool>;
+
+    fn pull(&self, image_ref: String) -> BoxFuture<bool>;
+
+    /// Creates a container, returning its id.
+    fn create(&self, name: Option<String>, request: CreateContainerRequest) -> BoxFuture<String>;
+
+    fn start(&self, container_id: String) -> BoxFuture<bool>;
+
+    fn inspect(&self, container_id: String) -> BoxFuture<InspectContainerResponse>;
+
+    fn rename(&self, container_id: String, container_name: String) -> BoxFuture<bool>;
+
+    /// Removes a container, stopping it first if it is running.
+    fn remove(&self, container_id: String) -> BoxFuture<bool>;
+
+    /// Lists all containers, including stopped ones.
+    fn list(&self) -> BoxFuture<Vec<ListContainersResponse>>;
+
+    fn list_images(&self) -> BoxFuture<Vec<ListImagesResponse>>;
+
+    /// Adds `repo:tag` as another name for `image`.
+    fn tag(&self, image: String, repo: String, tag: String) -> BoxFuture<bool>;
+
+    /// Removes the image name `image`, and the image itself if nothing else refers to it.
+    fn remove_image(&self, image: String) -> BoxFuture<bool>;
+}
+
+const HEALTH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Polls the container until its healthcheck reports healthy, or, for images without a
+/// healthcheck, until it has kept running for `settle`. Fails if it stops, turns unhealthy, or
+/// hasn't become healthy within `timeout`. A `timeout` shorter than `settle` is stretched to it, so
+/// containers without a healthcheck get the chance to settle.
+pub fn wait_until_healthy(
+    runtime: Rc<ContainerRuntime>,
+    container_id: String,
+    settle: Duration,
+    timeout: Duration,
+) -> impl Future<Item = (), Error = Error> {
+    let started = Instant::now();
+    let timeout = timeout.max(settle);
+    future::loop_fn((), move |()| {
+        let container_id = container_id.clone();
+        let runtime = runtime.clone();
+        Delay::new(Instant::now() + HEALTH_POLL_INTERVAL)
+            .map_err(|err| Error::from(err))
+            .and_then(move |_| runtime.inspect(container_id))
+            .and_then(move |inspect_container_response| {
+                let elapsed = started.elapsed();
+                let state = inspect_container_response.state;
+                let health_status = state.health.as_ref().map(|health| health.status.as_str());
+                if !state.running {
+                    return Err(ErrorKind::ContainerNotRunning(inspect_container_response.id).into());
+                }
+                match health_status {
+                    Some("healthy") => Ok(Loop::Break(())),
+                    Some("unhealthy") => {
+                        Err(ErrorKind::ContainerUnhealthy(inspect_container_response.id).into())
+                    }
+                    None if elapsed >= settle => Ok(Loop::Break(())),
+                    _ if elapsed >= timeout => {
+                        Err(ErrorKind::ContainerUnhealthy(inspect_container_response.id).into())
+                    }
+                    _ => {
+                        debug!("Waiting for container to become healthy");
+                        Ok(Loop::Continue(()))
+                    }
+                }
+            })
+    })
+}
+
+/// Runs containers through a CLI that takes the same commands as the `docker` one, e.g.
+/// `nerdctl` or `podman`.
+pub struct CommandRuntime {
+    program: String,
+}
+
+impl CommandRuntime {
+    pub fn new(program: String) -> Self {
+        Self { program }
+    }
+
+    /// Runs the program with `args` once the future is polled, returning its stdout.
+    fn run(&self, args: Vec<String>) -> impl Future<Item = String, Error = Error> {
+        let program = self.program.clone();
+        future::lazy(move || run_command(&program, &args))
+    }
+
+    /// Like `run`, but only reports whether the program succeeded.
+    fn run_status(&self, args: Vec<String>) -> BoxFuture<bool> {
+        let program = self.program.clone();
+        Box::new(future::lazy(move || -> Result<bool> {
+            debug!("Running {} {}", program, args.join(" "));
+            let output = Command::new(&program).args(&args).output()?;
+            if !output.status.success() {
+                warn!(
+                    "{} {} failed: {}",
+                    program,
+                    args.join(" "),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            Ok(output.status.success())
+        }))
+    }
+}
+
+fn run_command(program: &str, args: &[String]) -> Result<String> {
+    debug!("Running {} {}", program, args.join(" "));
+    let output = Command::new(program).args(args).output()?;
+    if output.status.success() {
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    } else {
+        Err(ErrorKind::RuntimeCommandFailed(format!(
+            "{} {}: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into())
+    }
+}
+
+fn args(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| String::from(*arg)).collect()
+}
+
+/// The `create` command line equivalent to `request`.
+fn create_args(name: Option<String>, request: CreateContainerRequest) -> Vec<String> {
+    let mut create_args = args(&["create"]);
+    if let Some(name) = name {
+        create_args.extend(vec![String::from("--name"), name]);
+    }
+    for (key, value) in request.labels {
+        create_args.extend(vec![String::from("--label"), format!("{}={}", key, value)]);
+    }
+    for env in request.env {
+        create_args.extend(vec![String::from("--env"), env]);
+    }
+    if let Some(host_config) = request.host_config {
+        if host_config.publish_all_ports == Some(true) {
+            create_args.push(String::from("--publish-all"));
+        }
+        for (container_port, bindings) in host_config.port_bindings.unwrap_or_default() {
+            for binding in bindings {
+                let host = match binding.host_ip {
+                    Some(host_ip) => format!("{}:{}", host_ip, binding.host_port),
+                    None => binding.host_port,
+                };
+                create_args.extend(vec![
+                    String::from("--publish"),
+                    format!("{}:{}", host, container_port),
+                ]);
+            }
+        }
+        for bind in host_config.binds {
+            create_args.extend(vec![String::from("--volume"), bind]);
+        }
+        create_args.extend(vec![
+            String::from("--restart"),
+            String::from(host_config.restart_policy.name),
+        ]);
+        if let Some(memory) = host_config.memory {
+            create_args.extend(vec![String::from("--memory"), memory.to_string()]);
+        }
+        if let Some(nano_cpus) = host_config.nano_cpus {
+            create_args.extend(vec![
+                String::from("--cpus"),
+                (nano_cpus as f64 / 1e9).to_string(),
+            ]);
+        }
+    }
+    create_args.push(request.image);
+    create_args
+}
+
+/// Ids printed one per line, without duplicates.
+fn ids(stdout: &str) -> Vec<String> {
+    let mut ids: Vec<String> = Vec::new();
+    for id in stdout.lines().map(str::trim).filter(|id| !id.is_empty()) {
+        if !ids.iter().any(|known| known == id) {
+            ids.push(String::from(id));
+        }
+    }
+    ids
+}
+
+#[derive(Deserialize)]
+struct InspectedContainer {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "Image")]
+    image_id: String,
+    #[serde(rename = "Config")]
+    config: InspectedContainerConfig,
+    #[serde(rename = "State")]
+    state: InspectedContainerState,
+}
+
+#[derive(Deserialize)]
+struct InspectedContainerConfig {
+    #[serde(rename = "Image")]
+    image: String,
+    #[serde(rename = "Labels")]
+    labels: Option<HashMap<String, String>>,
+}
+
+#[derive(Deserialize)]
+struct InspectedContainerState {
+    #[serde(rename = "Status")]
+    status: String,
+}
+
+#[derive(Deserialize)]
+struct InspectedImage {
+    #[serde(rename = "Id")]
+    id: String,
+    #[serde(rename = "RepoTags")]
+    repo_tags: Option<Vec<String>>,
+    #[serde(rename = "Created")]
+    created: String,
+}
+
+impl ContainerRuntime for CommandRuntime {
+    fn build(&self, context: PathBuf, image_name: String) -> BoxFuture<bool> {
+        let mut build_args = args(&["build", "--tag"]);
+        build_args.push(image_name);
+        build_args.push(context.to_string_lossy().into_owned());
+        Box::new(self.run(build_args).map(|_stdout| true))
+    }
+
+    fn pull(&self, image_ref: String) -> BoxFuture<bool> {
+        Box::new(self.run(vec![String::from("pull"), image_ref]).map(|_stdout| true))
+    }
+
+    fn create(&self, name: Option<String>, request: CreateContainerRequest) -> BoxFuture<String> {
+        Box::new(
+            self.run(create_args(name, request))
+                .map(|stdout| String::from(stdout.trim())),
+        )
+    }
+
+    fn start(&self, container_id: String) -> BoxFuture<bool> {
+        self.run_status(vec![String::from("start"), container_id])
+    }
+
+    fn inspect(&self, container_id: String) -> BoxFuture<InspectContainerResponse> {
+        let mut inspect_args = args(&["inspect", "--type", "container"]);
+        inspect_args.push(container_id.clone());
+        Box::new(self.run(inspect_args).and_then(move |stdout| -> Result<_> {
+            serde_json::from_str::<Vec<InspectContainerResponse>>(&stdout)?
+                .pop()
+                .ok_or_else(|| ErrorKind::ContainerNotRunning(container_id).into())
+        }))
+    }
+
+    fn rename(&self, container_id: String, container_name: String) -> BoxFuture<bool> {
+        self.run_status(vec![String::from("rename"), container_id, container_name])
+    }
+
+    fn remove(&self, container_id: String) -> BoxFuture<bool> {
+        self.run_status(vec![String::from("rm"), String::from("--force"), container_id])
+    }
+
+    fn list(&self) -> BoxFuture<Vec<ListContainersResponse>> {
+        let program = self.program.clone();
+        Box::new(
+            self.run(args(&["ps", "--all", "--quiet", "--no-trunc"]))
+                .and_then(move |stdout| -> Result<_> {
+                    let ids = ids(&stdout);
+                    if ids.is_empty() {
+                        return Ok(Vec::new());
+                    }
+                    let mut inspect_args = args(&["inspect", "--type", "container"]);
+                    inspect_args.extend(ids);
+                    let inspected: Vec<InspectedContainer> =
+                        serde_json::from_str(&run_command(&program, &inspect_args)?)?;
+                    Ok(inspected
+                        .into_iter()
+                        .map(|container| ListContainersResponse {
+                            id: container.id,
+                            image: container.config.image,
+                            image_id: container.image_id,
+                            labels: container.config.labels,
+                            state: container.state.status,
+                        })
+                        .collect())
+                }),
+        )
+    }
+
+    fn list_images(&self) -> BoxFuture<Vec<ListImagesResponse>> {
+        let program = self.program.clone();
+        Box::new(
+            self.run(args(&["images", "--quiet", "--no-trunc"]))
+                .and_then(move |stdout| -> Result<_> {
+                    let ids = ids(&stdout);
+                    if ids.is_empty() {
+                        return Ok(Vec::new());
+                    }
+                    let mut inspect_args = args(&["image", "inspect"]);
+                    inspect_args.extend(ids);
+                    let inspected: Vec<InspectedImage> =
+                        serde_json::from_str(&run_command(&program, &inspect_args)?)?;
+                    Ok(inspected
+                        .into_iter()
+                        .map(|image| ListImagesResponse {
+                            id: image.id,
+                            repo_tags: image.repo_tags.unwrap_or_default(),
+                            containers: 0,
+                            created: chrono::DateTime::parse_from_rfc3339(&image.created)
+                                .map(|created| created.timestamp())
+                                .unwrap_or(0),
+                        })
+                        .collect())
+                }),
+        )
+    }
+
+    fn tag(&self, image: String, repo: String, tag: String) -> BoxFuture<bool> {
+        self.run_status(vec![
+            String::from("tag"),
+            image,
+            format!("{}:{}", repo, tag),
+        ])
+    }
+
+    fn remove_image(&self, image: String) -> BoxFuture<bool> {
+        self.run_status(vec![String::from("rmi"), image])
+    }
+}
+
+/// A step of a deploy, sent to whoever embeds the daemon and asked for them, e.g. to show live
+/// progress.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DeployEvent {
+    /// Cloning the repo published under this IPNS name
+    Cloning(String),
+    /// Building this image
+    Building(String),
+    Pulling(String),
+    /// Tagging the image as this `repo:tag`
+    Tagging(String),
+    /// Creating a container from this image
+    Creating(String),
+    /// Starting the container with this id
+    Starting(String),
+    /// Renaming the container with this id to the given name
+    Renaming(String, String),
+    /// Removing the container with this id or name
+    Removing(String),
+    /// Removing this image name
+    RemovingImage(String),
+}
+
+/// Reports each change a runtime makes as a `DeployEvent` before making it.
+pub struct ObservedRuntime {
+    pub runtime: Rc<ContainerRuntime>,
+    pub events: mpsc::Sender<DeployEvent>,
+}
+
+impl ObservedRuntime {
+    fn send(&self, event: DeployEvent) {
+        // Nobody listening anymore is no reason to stop deploying
+        let _ = self.events.send(event);
+    }
+}
+
+impl ContainerRuntime for ObservedRuntime {
+    fn build(&self, context: PathBuf, image_name: String) -> BoxFuture<bool> {
+        self.send(DeployEvent::Building(image_name.clone()));
+        self.runtime.build(context, image_name)
+    }
+
+    fn pull(&self, image_ref: String) -> BoxFuture<bool> {
+        self.send(DeployEvent::Pulling(image_ref.clone()));
+        self.runtime.pull(image_ref)
+    }
+
+    fn create(&self, name: Option<String>, request: CreateContainerRequest) -> BoxFuture<String> {
+        self.send(DeployEvent::Creating(request.image.clone()));
+        self.runtime.create(name, request)
+    }
+
+    fn start(&self, container_id: String) -> BoxFuture<bool> {
+        self.send(DeployEvent::Starting(container_id.clone()));
+        self.runtime.start(container_id)
+    }
+
+    fn inspect(&self, container_id: String) -> BoxFuture<InspectContainerResponse> {
+        self.runtime.inspect(container_id)
+    }
+
+    fn rename(&self, container_id: String, container_name: String) -> BoxFuture<bool> {
+        self.send(DeployEvent::Renaming(
+            container_id.clone(),
+            container_name.clone(),
+        ));
+        self.runtime.rename(container_id, container_name)
+    }
+
+    fn remove(&self, container_id: String) -> BoxFuture<bool> {
+        self.send(DeployEvent::Removing(container_id.clone()));
+        self.runtime.remove(container_id)
+    }
+
+    fn list(&self) -> BoxFuture<Vec<ListContainersResponse>> {
+        self.runtime.list()
+    }
+
+    fn list_images(&self) -> BoxFuture<Vec<ListImagesResponse>> {
+        self.runtime.list_images()
+    }
+
+    fn tag(&self, image: String, repo: String, tag: String) -> BoxFuture<bool> {
+        self.send(DeployEvent::Tagging(format!("{}:{}", repo, tag)));
+        self.runtime.tag(image, repo, tag)
+    }
+
+    fn remove_image(&self, image: String) -> BoxFuture<bool> {
+        self.send(DeployEvent::RemovingImage(image.clone()));
+        self.runtime.remove_image(image)
+    }
+}
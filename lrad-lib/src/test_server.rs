@@ -0,0 +1,100 @@
+//! A stand-in for the HTTP APIs lrad talks to, e.g. the Docker daemon or the IPFS API server.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// Reads a request, headers and body, off `stream`.
+fn read_request(stream: &TcpStream) -> String {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = String::new();
+    let mut content_length = 0;
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        if line.to_lowercase().starts_with("content-length:") {
+            content_length = line["content-length:".len()..].trim().parse().unwrap();
+        }
+        request.push_str(&line);
+        if line == "\r\n" {
+            break;
+        }
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).unwrap();
+    request.push_str(&String::from_utf8(body).unwrap());
+    request
+}
+
+/// Reads the next request and answers it with `response`.
+fn answer(listener: &TcpListener, response: &str) -> String {
+    let (stream, _) = listener.accept().unwrap();
+    let request = read_request(&stream);
+    (&stream).write_all(response.as_bytes()).unwrap();
+    request
+}
+
+/// Answers a single HTTP request with `response`, handing back the request it received.
+/// Returns the address it listens on, e.g. `127.0.0.1:34567`.
+pub fn serve_once(response: &'static str) -> (String, thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = thread::spawn(move || answer(&listener, response));
+    (addr, handle)
+}
+
+/// Like `serve_once`, answering a request, each on its own connection, with each of `responses`
+/// in turn.
+pub fn serve(responses: Vec<&'static str>) -> (String, thread::JoinHandle<Vec<String>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = thread::spawn(move || {
+        responses
+            .into_iter()
+            .map(|response| answer(&listener, response))
+            .collect()
+    });
+    (addr, handle)
+}
+
+/// Answers every request, each on its own thread, with what `respond` makes of its request line,
+/// e.g. `GET /v1.39/images/json HTTP/1.1`. Where that is `None` the connection is held until the
+/// client hangs up, standing in for a pull or build that is still going. Returns the address it
+/// listens on and the request lines received so far, with `abandoned <request line>` added once a
+/// held connection is hung up on.
+pub fn serve_each<F>(respond: F) -> (String, Arc<Mutex<Vec<String>>>)
+where
+    F: Fn(&str) -> Option<&'static str> + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let respond = Arc::new(respond);
+    let received = requests.clone();
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = stream.unwrap();
+            let requests = received.clone();
+            let respond = respond.clone();
+            thread::spawn(move || {
+                let request = read_request(&stream);
+                let request_line = request.lines().next().unwrap_or_default().to_string();
+                requests.lock().unwrap().push(request_line.clone());
+                match respond(&request_line) {
+                    Some(response) => {
+                        let _ = (&stream).write_all(response.as_bytes());
+                    }
+                    None => {
+                        let _ = (&stream).read_to_end(&mut Vec::new());
+                        requests
+                            .lock()
+                            .unwrap()
+                            .push(format!("abandoned {}", request_line));
+                    }
+                }
+            });
+        }
+    });
+    (addr, requests)
+}
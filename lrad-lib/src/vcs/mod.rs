@@ -1,14 +1,468 @@
-use git2::{build::RepoBuilder, Repository};
-use std::path::Path;
+use git2::{
+    build::RepoBuilder, ErrorCode, ObjectType, Repository, RepositoryState, TreeWalkMode,
+    TreeWalkResult,
+};
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+use crate::config::TrustedSigners;
+use crate::error::ErrorKind;
 
 pub fn clone_bare(url: &str, into: &Path) -> Result<Repository, git2::Error> {
     RepoBuilder::new().bare(true).clone(url, into)
 }
 
+/// Runs git with `args` in `dir`. A missing git binary or a non-zero exit is turned into a
+/// `GitCommandFailed` error carrying what went wrong, rather than going unnoticed.
+pub fn run_git<I, S>(dir: &Path, args: I) -> crate::error::Result<Output>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let args: Vec<OsString> = args
+        .into_iter()
+        .map(|arg| arg.as_ref().to_os_string())
+        .collect();
+    let command = args
+        .iter()
+        .map(|arg| arg.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let output = match Command::new("git").args(&args).current_dir(dir).output() {
+        Ok(output) => output,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+            return Err(ErrorKind::GitCommandFailed(
+                command,
+                String::from("the git binary was not found, is git installed?"),
+            )
+            .into())
+        }
+        Err(err) => return Err(err.into()),
+    };
+    debug!("{:?}", output);
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(ErrorKind::GitCommandFailed(command, stderr).into());
+    }
+    Ok(output)
+}
+
+/// Writes the files that let the repo be cloned over the dumb HTTP protocol, e.g. through an
+/// IPFS gateway, like `git update-server-info` does.
+pub fn update_server_info(repo: &Repository) -> crate::error::Result<()> {
+    let mut refs = Vec::new();
+    for reference in repo.references()? {
+        let reference = reference?;
+        // Symbolic refs are listed by what they point to
+        if let (Some(name), Some(oid)) = (reference.name(), reference.target()) {
+            refs.push((String::from(name), oid));
+        }
+    }
+    refs.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut info_refs = String::new();
+    for (name, oid) in refs {
+        info_refs.push_str(&format!("{}\t{}\n", oid, name));
+        let object = repo.find_object(oid, None)?;
+        if object.kind() == Some(ObjectType::Tag) {
+            let peeled = object.peel(ObjectType::Any)?.id();
+            info_refs.push_str(&format!("{}\t{}^{{}}\n", peeled, name));
+        }
+    }
+    fs::create_dir_all(repo.path().join("info"))?;
+    fs::write(repo.path().join("info").join("refs"), info_refs)?;
+
+    let objects_dir = repo.path().join("objects");
+    let mut packs = Vec::new();
+    if objects_dir.join("pack").is_dir() {
+        for entry in fs::read_dir(objects_dir.join("pack"))? {
+            let file_name = entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            if file_name.ends_with(".pack") {
+                packs.push(format!("P {}\n", file_name));
+            }
+        }
+    }
+    packs.sort();
+    packs.push(String::from("\n"));
+    fs::create_dir_all(objects_dir.join("info"))?;
+    fs::write(objects_dir.join("info").join("packs"), packs.concat())?;
+    Ok(())
+}
+
+/// Checks out the repo at `published`, e.g. a bare repo fetched from IPFS, into `into`.
+pub fn checkout(published: &Path, into: &Path) -> Result<Repository, git2::Error> {
+    RepoBuilder::new().clone(&published.to_string_lossy(), into)
+}
+
+/// Rejects repos in the middle of an operation that would leave a half-finished tree behind.
+/// A detached HEAD is fine to push from.
+pub fn check_state(repo: &Repository) -> Result<(), VcsError> {
+    match repo.state() {
+        RepositoryState::Clean => Ok(()),
+        RepositoryState::Merge => Err(VcsError::RepoMidMerge),
+        RepositoryState::Revert | RepositoryState::RevertSequence => Err(VcsError::RepoMidRevert),
+        RepositoryState::CherryPick | RepositoryState::CherryPickSequence => {
+            Err(VcsError::RepoMidCherryPick)
+        }
+        RepositoryState::Bisect => Err(VcsError::RepoMidBisect),
+        RepositoryState::Rebase
+        | RepositoryState::RebaseInteractive
+        | RepositoryState::RebaseMerge
+        | RepositoryState::ApplyMailboxOrRebase => Err(VcsError::RepoMidRebase),
+        RepositoryState::ApplyMailbox => Err(VcsError::RepoMidApplyMailbox),
+    }
+}
+
+/// Lists the files committed at HEAD that the repo's ignore rules match, e.g. a `.env` added with
+/// `git add -f`. A push publishes every committed file, so these would be shipped along.
+pub fn committed_ignored_files(repo: &Repository) -> crate::error::Result<Vec<PathBuf>> {
+    let tree = repo.head()?.peel_to_tree()?;
+    let mut ignored = Vec::new();
+    let mut ignore_err = None;
+    tree.walk(TreeWalkMode::PreOrder, |dir, entry| {
+        let name = match entry.name() {
+            Some(name) if entry.kind() == Some(ObjectType::Blob) => name,
+            _ => return TreeWalkResult::Ok,
+        };
+        let path = Path::new(dir).join(name);
+        match repo.status_should_ignore(&path) {
+            Ok(true) => ignored.push(path),
+            Ok(false) => {}
+            Err(err) => {
+                ignore_err = Some(err);
+                return TreeWalkResult::Abort;
+            }
+        }
+        TreeWalkResult::Ok
+    })?;
+    match ignore_err {
+        Some(err) => Err(err.into()),
+        None => Ok(ignored),
+    }
+}
+
+const SSH_SIGNATURE_ARMOR: &[u8] = b"-----BEGIN SSH SIGNATURE-----";
+const PGP_SIGNATURE_ARMOR: &[u8] = b"-----BEGIN PGP SIGNATURE-----";
+
+/// Checks that the HEAD commit of `repo` is signed by one of the `signers`, with gpgv or
+/// ssh-keygen depending on the kind of signature.
+pub fn verify_head_signature(
+    repo: &Repository,
+    signers: &TrustedSigners,
+) -> crate::error::Result<()> {
+    let head = repo.head()?.peel_to_commit()?.id();
+    let (signature, signed_data) = match repo.extract_signature(&head, None) {
+        Ok(extracted) => extracted,
+        Err(ref err) if err.code() == ErrorCode::NotFound => {
+            return Err(VcsError::HeadCommitUnsigned.into())
+        }
+        Err(err) => return Err(err.into()),
+    };
+    let dir = tempfile::tempdir()?;
+    let signature_path = dir.path().join("signature");
+    let data_path = dir.path().join("data");
+    fs::write(&signature_path, &*signature)?;
+    fs::write(&data_path, &*signed_data)?;
+    let output = if signature.starts_with(SSH_SIGNATURE_ARMOR) {
+        match &signers.ssh_allowed_signers {
+            Some(allowed_signers) => {
+                verify_ssh_signature(allowed_signers, &signature_path, &signed_data)?
+            }
+            None => {
+                warn!("HEAD commit {} has an SSH signature, but no ssh_allowed_signers", head);
+                return Err(VcsError::HeadSignatureUntrusted.into());
+            }
+        }
+    } else if signature.starts_with(PGP_SIGNATURE_ARMOR) {
+        match &signers.gpg_keyring {
+            Some(keyring) => Command::new("gpgv")
+                .arg("--keyring")
+                .arg(keyring)
+                .arg(&signature_path)
+                .arg(&data_path)
+                .output()?,
+            None => {
+                warn!("HEAD commit {} has a PGP signature, but no gpg_keyring", head);
+                return Err(VcsError::HeadSignatureUntrusted.into());
+            }
+        }
+    } else {
+        warn!("HEAD commit {} has a signature of an unknown kind", head);
+        return Err(VcsError::HeadSignatureUntrusted.into());
+    };
+    debug!("{:?}", output);
+    if output.status.success() {
+        Ok(())
+    } else {
+        warn!(
+            "HEAD commit {} is not signed by a trusted key: {}",
+            head,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+        Err(VcsError::HeadSignatureUntrusted.into())
+    }
+}
+
+/// Looks up who made the SSH signature in `allowed_signers`, then verifies it was them, as git
+/// itself does.
+fn verify_ssh_signature(
+    allowed_signers: &Path,
+    signature_path: &Path,
+    signed_data: &[u8],
+) -> crate::error::Result<Output> {
+    let principals = Command::new("ssh-keygen")
+        .args(&["-Y", "find-principals", "-f"])
+        .arg(allowed_signers)
+        .arg("-s")
+        .arg(signature_path)
+        .output()?;
+    let principal = match String::from_utf8_lossy(&principals.stdout).lines().next() {
+        Some(principal) if principals.status.success() => String::from(principal),
+        _ => return Ok(principals),
+    };
+    let mut verify = Command::new("ssh-keygen")
+        .args(&["-Y", "verify", "-n", "git", "-f"])
+        .arg(allowed_signers)
+        .arg("-I")
+        .arg(principal)
+        .arg("-s")
+        .arg(signature_path)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    verify
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(signed_data)?;
+    Ok(verify.wait_with_output()?)
+}
+
 #[derive(Debug)]
 pub enum VcsError {
     RepoShouldNotBeBare,
-    RepoNotClean,
+    RepoMidMerge,
+    RepoMidRevert,
+    RepoMidCherryPick,
+    RepoMidBisect,
+    RepoMidRebase,
+    RepoMidApplyMailbox,
     RepoHasConflicts,
     RepoHasUnstagedChanges,
+    HeadCommitUnsigned,
+    /// Signed, but not by any of the trusted signers, which is logged in more detail
+    HeadSignatureUntrusted,
+    /// HEAD has files the ignore rules match, which are logged
+    HeadHasIgnoredFiles,
+}
+
+impl fmt::Display for VcsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            VcsError::RepoShouldNotBeBare => "the repo is bare, run lrad from a checkout instead",
+            VcsError::RepoMidMerge => {
+                "a merge is in progress, finish it with git commit or run git merge --abort"
+            }
+            VcsError::RepoMidRevert => {
+                "a revert is in progress, run git revert --continue or git revert --abort"
+            }
+            VcsError::RepoMidCherryPick => {
+                "a cherry-pick is in progress, run git cherry-pick --continue or git cherry-pick --abort"
+            }
+            VcsError::RepoMidBisect => "a bisect is in progress, run git bisect reset",
+            VcsError::RepoMidRebase => {
+                "a rebase is in progress, run git rebase --continue or git rebase --abort"
+            }
+            VcsError::RepoMidApplyMailbox => {
+                "git am is in progress, run git am --continue or git am --abort"
+            }
+            VcsError::RepoHasConflicts => "the index has conflicts, resolve them and commit",
+            VcsError::RepoHasUnstagedChanges => "there are unstaged changes, commit or stash them",
+            VcsError::HeadCommitUnsigned => "the HEAD commit is not signed, refusing to deploy it",
+            VcsError::HeadSignatureUntrusted => {
+                "the HEAD commit is not signed by a trusted key, refusing to deploy it"
+            }
+            VcsError::HeadHasIgnoredFiles => {
+                "HEAD has files matched by .gitignore, remove them or push with --include-ignored"
+            }
+        })
+    }
+}
+
+impl std::error::Error for VcsError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::{Oid, Signature};
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn repo_with_commit() -> (TempDir, Repository, Oid) {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = Repository::init(dir.path()).unwrap();
+        let oid = {
+            let sig = Signature::now("lrad", "lrad@example.com").unwrap();
+            let tree_id = repo.index().unwrap().write_tree().unwrap();
+            let tree = repo.find_tree(tree_id).unwrap();
+            repo.commit(Some("HEAD"), &sig, &sig, "Initial commit", &tree, &[])
+                .unwrap()
+        };
+        (dir, repo, oid)
+    }
+
+    /// Commits to a new repo with the git CLI, signing the commit with the SSH key if there is one.
+    fn repo_with_git_commit(dir: &Path, signing_key: Option<&Path>) -> Repository {
+        let git = |args: &[&str]| {
+            let mut command = Command::new("git");
+            command
+                .args(&["-c", "user.name=lrad", "-c", "user.email=lrad@example.com"])
+                .current_dir(dir);
+            if let Some(signing_key) = signing_key {
+                command
+                    .args(&["-c", "gpg.format=ssh", "-c"])
+                    .arg(format!("user.signingkey={}", signing_key.display()));
+            }
+            assert!(command.args(args).status().unwrap().success());
+        };
+        git(&["init", "-q"]);
+        let sign = if signing_key.is_some() { "-S" } else { "--no-gpg-sign" };
+        git(&["commit", "-q", "--allow-empty", sign, "-m", "Initial commit"]);
+        Repository::open(dir).unwrap()
+    }
+
+    #[test]
+    fn force_added_ignored_files_are_found() {
+        let (dir, repo, head) = repo_with_commit();
+        fs::write(dir.path().join(".gitignore"), ".env\ntarget/\n").unwrap();
+        fs::write(dir.path().join(".env"), "SECRET=1").unwrap();
+        fs::write(dir.path().join("app.txt"), "app").unwrap();
+        fs::create_dir(dir.path().join("target")).unwrap();
+        fs::write(dir.path().join("target").join("app"), "binary").unwrap();
+        let mut index = repo.index().unwrap();
+        for path in &[".gitignore", ".env", "app.txt", "target/app"] {
+            index.add_path(Path::new(path)).unwrap();
+        }
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let parent = repo.find_commit(head).unwrap();
+        let sig = Signature::now("lrad", "lrad@example.com").unwrap();
+        repo.commit(Some("HEAD"), &sig, &sig, "Add files", &tree, &[&parent])
+            .unwrap();
+        assert_eq!(
+            committed_ignored_files(&repo).unwrap(),
+            vec![PathBuf::from(".env"), PathBuf::from("target/app")]
+        );
+    }
+
+    #[test]
+    fn failed_git_command_says_why() {
+        let dir = tempfile::tempdir().unwrap();
+        match run_git(dir.path(), &["clone", "/nonexistent/lrad"]) {
+            Err(box ErrorKind::GitCommandFailed(command, stderr)) => {
+                assert_eq!(command, "clone /nonexistent/lrad");
+                assert!(stderr.contains("/nonexistent/lrad"), "{}", stderr);
+            }
+            other => panic!("expected GitCommandFailed, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn server_info_matches_what_git_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = repo_with_git_commit(dir.path(), None);
+        let tag = &[
+            "-c",
+            "user.name=lrad",
+            "-c",
+            "user.email=lrad@example.com",
+            "tag",
+            "-a",
+            "v1",
+            "-m",
+            "v1",
+        ];
+        run_git(dir.path(), tag).unwrap();
+        update_server_info(&repo).unwrap();
+        let ours = fs::read_to_string(repo.path().join("info").join("refs")).unwrap();
+        run_git(dir.path(), &["update-server-info"]).unwrap();
+        let theirs = fs::read_to_string(repo.path().join("info").join("refs")).unwrap();
+        assert_eq!(ours, theirs);
+        assert!(ours.contains("refs/tags/v1^{}"));
+    }
+
+    #[test]
+    fn published_repo_is_checked_out() {
+        let published = tempfile::tempdir().unwrap();
+        let published_repo = repo_with_git_commit(published.path(), None);
+        let into = tempfile::tempdir().unwrap();
+        let repo = checkout(published.path(), &into.path().join("checkout")).unwrap();
+        assert!(repo.workdir().is_some());
+        assert_eq!(
+            repo.head().unwrap().target(),
+            published_repo.head().unwrap().target()
+        );
+    }
+
+    #[test]
+    fn only_a_head_signed_by_a_trusted_key_is_deployed() {
+        let keys = tempfile::tempdir().unwrap();
+        let key = keys.path().join("id_ed25519");
+        assert!(Command::new("ssh-keygen")
+            .args(&["-q", "-t", "ed25519", "-N", "", "-f"])
+            .arg(&key)
+            .status()
+            .unwrap()
+            .success());
+        let public_key = fs::read_to_string(key.with_extension("pub")).unwrap();
+        let allowed_signers = keys.path().join("allowed_signers");
+        fs::write(&allowed_signers, format!("lrad@example.com {}", public_key)).unwrap();
+        let signers = TrustedSigners {
+            gpg_keyring: None,
+            ssh_allowed_signers: Some(allowed_signers),
+        };
+
+        let signed = tempfile::tempdir().unwrap();
+        let repo = repo_with_git_commit(signed.path(), Some(&key));
+        assert!(verify_head_signature(&repo, &signers).is_ok());
+
+        let unsigned = tempfile::tempdir().unwrap();
+        let repo = repo_with_git_commit(unsigned.path(), None);
+        match verify_head_signature(&repo, &signers) {
+            Err(box crate::error::ErrorKind::VcsError(VcsError::HeadCommitUnsigned)) => {}
+            other => panic!("expected HeadCommitUnsigned, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn detached_head_is_allowed() {
+        let (_dir, repo, oid) = repo_with_commit();
+        repo.set_head_detached(oid).unwrap();
+        assert!(repo.head_detached().unwrap());
+        assert!(check_state(&repo).is_ok());
+    }
+
+    #[test]
+    fn mid_merge_is_reported() {
+        let (_dir, repo, oid) = repo_with_commit();
+        fs::write(repo.path().join("MERGE_HEAD"), format!("{}\n", oid)).unwrap();
+        match check_state(&repo) {
+            Err(VcsError::RepoMidMerge) => {}
+            other => panic!("expected RepoMidMerge, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn mid_rebase_is_reported() {
+        let (_dir, repo, _oid) = repo_with_commit();
+        fs::create_dir(repo.path().join("rebase-merge")).unwrap();
+        match check_state(&repo) {
+            Err(VcsError::RepoMidRebase) => {}
+            other => panic!("expected RepoMidRebase, got {:?}", other),
+        }
+    }
 }